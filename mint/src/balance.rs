@@ -0,0 +1,385 @@
+// connections spread over servers, each with its own key and fake header,
+// tried in the order of a strategy, failing over to the next when one fails
+
+use std::{
+	cell::{Cell, RefCell},
+	ops::Deref,
+	rc::Rc,
+	time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::KeySizeUser;
+use log::*;
+use tokio::net::TcpStream;
+
+use crate::{Upstream, fake::Header, key::*};
+
+// a failed server is tried after the others for this long
+const DOWN_FOR: Duration = Duration::from_secs(30);
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum Strategy {
+	// in turns
+	RoundRobin,
+	// the one with the fewest connections
+	LeastConn,
+	// the one connecting the fastest, probed periodically
+	Latency,
+}
+
+pub struct Server<C: KeySizeUser> {
+	pub name: String,
+	pub upstream: Upstream,
+	// replaced on reload
+	psk: RefCell<Rc<Psk<C>>>,
+	pub fake_header: Header,
+	conns: Cell<usize>,
+	// of the last probe, None if it failed or there's none yet
+	latency: Cell<Option<Duration>>,
+	down_until: Cell<Option<Instant>>,
+}
+
+impl<C: KeySizeUser> Server<C> {
+	pub fn new(name: String, upstream: Upstream, psk: Psk<C>, fake_header: Header) -> Self {
+		Self {
+			name,
+			upstream,
+			psk: RefCell::new(Rc::new(psk)),
+			fake_header,
+			conns: Cell::new(0),
+			latency: Cell::new(None),
+			down_until: Cell::new(None),
+		}
+	}
+
+	// the current one, for a new connection
+	pub fn psk(&self) -> Rc<Psk<C>> {
+		self.psk.borrow().clone()
+	}
+
+	// counted until dropped
+	pub fn conn(self: &Rc<Self>) -> Conn<C> {
+		self.conns.set(self.conns.get() + 1);
+		Conn(self.clone())
+	}
+
+	pub fn fail(&self) {
+		warn!(
+			"server {} failed, tried after the others for {}s",
+			self.name,
+			DOWN_FOR.as_secs()
+		);
+		self.down_until.set(Some(Instant::now() + DOWN_FOR));
+	}
+
+	pub fn ok(&self) {
+		self.down_until.set(None);
+	}
+
+	fn is_down(&self, now: Instant) -> bool {
+		self.down_until.get().is_some_and(|t| t > now)
+	}
+
+	// how long a TCP connection takes
+	async fn probe(&self, timeout: Option<Duration>) {
+		let addrs = self.upstream.addrs.borrow().clone();
+		let start = Instant::now();
+		let connect = TcpStream::connect(&addrs[..]);
+		let r = match timeout {
+			Some(t) => tokio::time::timeout(t, connect).await.ok(),
+			None => Some(connect.await),
+		};
+		let latency = match r {
+			Some(Ok(_)) => Some(start.elapsed()),
+			Some(Err(e)) => {
+				debug!("error probing server {}: {e}", self.name);
+				None
+			}
+			None => {
+				debug!("timeout probing server {}", self.name);
+				None
+			}
+		};
+		debug!("server {} latency: {latency:?}", self.name);
+		self.latency.set(latency);
+	}
+}
+
+// a connection to a server
+pub struct Conn<C: KeySizeUser>(Rc<Server<C>>);
+
+impl<C: KeySizeUser> Deref for Conn<C> {
+	type Target = Server<C>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl<C: KeySizeUser> Drop for Conn<C> {
+	fn drop(&mut self) {
+		self.0.conns.set(self.0.conns.get() - 1);
+	}
+}
+
+pub struct Balancer<C: KeySizeUser> {
+	servers: Vec<Rc<Server<C>>>,
+	strategy: Strategy,
+	next: Cell<usize>,
+}
+
+impl<C: KeySizeUser + 'static> Balancer<C> {
+	pub fn new(servers: Vec<Server<C>>, strategy: Strategy) -> Self {
+		Self {
+			servers: servers.into_iter().map(Rc::new).collect(),
+			strategy,
+			next: Cell::new(0),
+		}
+	}
+
+	// in the order to try, failed ones last
+	// ties are in turns
+	pub fn pick(&self) -> Vec<Rc<Server<C>>> {
+		let n = self.servers.len();
+		let start = self.next.get();
+		self.next.set((start + 1) % n);
+		let mut order: Vec<_> = (0..n)
+			.map(|i| self.servers[(start + i) % n].clone())
+			.collect();
+		match self.strategy {
+			Strategy::RoundRobin => {}
+			Strategy::LeastConn => order.sort_by_key(|s| s.conns.get()),
+			Strategy::Latency => order.sort_by_key(|s| s.latency.get().unwrap_or(Duration::MAX)),
+		}
+		let now = Instant::now();
+		order.sort_by_key(|s| s.is_down(now));
+		order
+	}
+
+	// false if there's no server by the name
+	// keys are only reloaded on SIGHUP
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn set_psk(&self, name: &str, psk: Psk<C>) -> bool {
+		let Some(s) = self.servers.iter().find(|s| s.name == name) else {
+			return false;
+		};
+		*s.psk.borrow_mut() = Rc::new(psk);
+		true
+	}
+
+	// for the latency strategy, in the background
+	pub fn probe(&self, interval: Duration, timeout: Option<Duration>) {
+		if !matches!(self.strategy, Strategy::Latency) {
+			return;
+		}
+		for s in &self.servers {
+			let s = s.clone();
+			tokio::task::spawn_local(async move {
+				let mut tick = tokio::time::interval(interval);
+				loop {
+					tick.tick().await;
+					s.probe(timeout).await;
+				}
+			});
+		}
+	}
+
+	// hosts of servers, in the background
+	pub fn resolve(&self, interval: Duration) {
+		for s in &self.servers {
+			if s.upstream.is_literal() {
+				continue;
+			}
+			let s = s.clone();
+			tokio::task::spawn_local(async move {
+				let mut tick = tokio::time::interval(interval);
+				// the first one is right away, it's just resolved
+				tick.tick().await;
+				loop {
+					tick.tick().await;
+					s.upstream.resolve().await;
+				}
+			});
+		}
+	}
+}
+
+// a server in the servers file
+pub struct Entry<C: KeySizeUser> {
+	pub name: String,
+	pub addr: String,
+	pub psk: Psk<C>,
+	// path of the template, the default one if None
+	pub fake_header: Option<String>,
+}
+
+pub fn load_servers<C: Cipher>(path: &str) -> Option<Vec<Entry<C>>> {
+	let s = std::fs::read_to_string(path)
+		.inspect_err(|e| error!("failed to read \"{path}\": {e}"))
+		.ok()?;
+	let servers = parse_servers(&s)?;
+	if servers.is_empty() {
+		error!("no servers in \"{path}\"");
+		return None;
+	}
+	Some(servers)
+}
+
+// lines of name, address, key, and optionally the fake header template
+// the key is a bare one, or the path of a PSK file, the newest valid one in it is used
+fn parse_servers<C: Cipher>(s: &str) -> Option<Vec<Entry<C>>> {
+	let mut servers: Vec<Entry<C>> = Vec::new();
+	for l in s
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with('#'))
+	{
+		let (name, addr, key, fake_header) = match l.split_whitespace().collect::<Vec<_>>()[..] {
+			[name, addr, key] => (name, addr, key, None),
+			[name, addr, key, fake] => (name, addr, key, Some(fake.to_owned())),
+			_ => {
+				error!("invalid line, expecting a name, an address and a key: \"{l}\"");
+				return None;
+			}
+		};
+		if servers.iter().any(|s| s.name == name) {
+			error!("duplicate server name \"{name}\"");
+			return None;
+		}
+		let psk = if std::path::Path::new(key).is_file() {
+			init_psk(key)
+		} else {
+			parse_psk(key.as_bytes())
+		};
+		let Some(psk) = psk else {
+			error!("invalid key of server \"{name}\", expecting a key or a PSK file");
+			return None;
+		};
+		servers.push(Entry {
+			name: name.to_owned(),
+			addr: addr.to_owned(),
+			psk,
+			fake_header,
+		});
+	}
+	Some(servers)
+}
+
+#[cfg(test)]
+mod tests {
+	use chacha20poly1305::ChaCha8Poly1305 as C;
+
+	use super::*;
+
+	#[test]
+	fn test_servers() {
+		let (a, b) = (gen_psk::<C>(), gen_psk::<C>());
+		let servers = parse_servers::<C>(&format!(
+			"# name address key\na a.example.com:443 {a}\n\nb 192.0.2.1:8080\t{b} conf/b.txt\n"
+		))
+		.unwrap();
+		assert_eq!(
+			vec![
+				("a", "a.example.com:443", None),
+				("b", "192.0.2.1:8080", Some("conf/b.txt")),
+			],
+			servers
+				.iter()
+				.map(|s| (&s.name[..], &s.addr[..], s.fake_header.as_deref()))
+				.collect::<Vec<_>>()
+		);
+
+		// the newest valid key in a PSK file
+		let path = std::env::temp_dir().join(format!("mint-test-{}", rand::random::<u32>()));
+		let now = crate::replay::now();
+		std::fs::write(
+			&path,
+			format!(
+				"1 {} 0 {a}\n2 {} {} {b}\n3 {} {} {a}\n",
+				now - 20,
+				now - 10,
+				now + 100,
+				now - 5,
+				now - 1,
+			),
+		)
+		.unwrap();
+		let servers = parse_servers::<C>(&format!("a x:1 {}\n", path.display()));
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(Some(now + 100), servers.unwrap()[0].psk.not_after);
+		assert!(parse_servers::<C>(&format!("a x:1 {}\n", path.display())).is_none());
+
+		assert!(parse_servers::<C>("a a.example.com:443\n").is_none());
+		assert!(parse_servers::<C>(&format!("a x:1 {a}\na y:1 {b}\n")).is_none());
+	}
+
+	fn server(name: &str) -> Server<C> {
+		Server::new(
+			name.into(),
+			Upstream {
+				addr: String::new(),
+				addrs: Default::default(),
+				dns: None,
+				failures: Cell::new(0),
+				tls: None,
+				ws: String::new(),
+				hosts: vec![],
+			},
+			Psk::new(Default::default()),
+			Header::default(),
+		)
+	}
+
+	fn names(servers: &[Rc<Server<C>>]) -> Vec<&str> {
+		servers.iter().map(|s| &s.name[..]).collect()
+	}
+
+	#[test]
+	fn test_pick() {
+		let b = Balancer::new(
+			vec![server("a"), server("b"), server("c")],
+			Strategy::RoundRobin,
+		);
+		assert_eq!(vec!["a", "b", "c"], names(&b.pick()));
+		let order = b.pick();
+		assert_eq!(vec!["b", "c", "a"], names(&order));
+		// failed ones last
+		order[0].fail();
+		assert_eq!(vec!["c", "a", "b"], names(&b.pick()));
+		assert_eq!(vec!["a", "c", "b"], names(&b.pick()));
+		order[0].ok();
+		assert_eq!(vec!["b", "c", "a"], names(&b.pick()));
+
+		let b = Balancer::new(
+			vec![server("a"), server("b"), server("c")],
+			Strategy::LeastConn,
+		);
+		let a = b.pick()[0].conn();
+		let _b = b.pick()[0].conn();
+		assert_eq!(vec!["c", "a", "b"], names(&b.pick()));
+		drop(a);
+		assert_eq!(vec!["a", "c", "b"], names(&b.pick()));
+
+		let b = Balancer::new(
+			vec![server("a"), server("b"), server("c")],
+			Strategy::Latency,
+		);
+		b.servers[0].latency.set(Some(Duration::from_millis(30)));
+		b.servers[2].latency.set(Some(Duration::from_millis(10)));
+		assert_eq!(vec!["c", "a", "b"], names(&b.pick()));
+	}
+
+	#[test]
+	fn test_set_psk() {
+		let b = Balancer::new(vec![server("a"), server("b")], Strategy::RoundRobin);
+		let old = b.servers[1].psk();
+		let mut psk = Psk::new(Default::default());
+		psk.not_after = Some(1);
+		assert!(b.set_psk("b", psk));
+		assert_eq!(Some(1), b.servers[1].psk().not_after);
+		assert_eq!(None, b.servers[0].psk().not_after);
+		// kept by those using it
+		assert_eq!(None, old.not_after);
+		assert!(!b.set_psk("c", Psk::new(Default::default())));
+	}
+}
@@ -1,22 +1,320 @@
-use std::fs::read_to_string;
+// fake HTTP headers in front of handshake messages
+// they're templates, rendered for each message, so it's not the same bytes every time
+//
+// placeholders:
+//	{path}            a random path
+//	{host}            one of the hosts given
+//	{date}            now, in HTTP date format
+//	{token}           random alphanumerics, 32 of them, or N with {token:N}
+//	{content_length}  length of the message following the header
+//	{ws_key}          a random Sec-WebSocket-Key
+//	{ws_accept}       Sec-WebSocket-Accept of the key in the request, in responses
+// unknown ones are kept as is
+//
+// if the header says Transfer-Encoding: chunked,
+// the message follows as a single chunk, with its chunk size line
 
+use std::{fs::read_to_string, time::SystemTime};
+
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use chacha20poly1305::aead::bytes::{BufMut, BytesMut};
 use log::*;
+use sha1::{Digest, Sha1};
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const ALNUM: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+pub const UA: &str = "User-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+
+// built-in headers, instead of the file
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum Preset {
+	// a WebSocket upgrade, answered with 101
+	Websocket,
+	// a chunked upload
+	ChunkedPost,
+}
+
+impl Preset {
+	fn template(self, client: bool) -> String {
+		let lines: &[&str] = match (self, client) {
+			(Preset::Websocket, true) => &[
+				"GET {path} HTTP/1.1",
+				"Host: {host}",
+				UA,
+				"Upgrade: websocket",
+				"Connection: Upgrade",
+				"Sec-WebSocket-Key: {ws_key}",
+				"Sec-WebSocket-Version: 13",
+				"Cookie: sid={token}",
+			],
+			(Preset::Websocket, false) => &[
+				"HTTP/1.1 101 Switching Protocols",
+				"Upgrade: websocket",
+				"Connection: Upgrade",
+				"Sec-WebSocket-Accept: {ws_accept}",
+			],
+			(Preset::ChunkedPost, true) => &[
+				"POST {path} HTTP/1.1",
+				"Host: {host}",
+				UA,
+				"Content-Type: application/octet-stream",
+				"Cookie: sid={token}",
+				"Transfer-Encoding: chunked",
+			],
+			(Preset::ChunkedPost, false) => &[
+				"HTTP/1.1 200 OK",
+				"Date: {date}",
+				"Content-Type: application/octet-stream",
+				"Transfer-Encoding: chunked",
+			],
+		};
+		lines.join("\n")
+	}
+}
+
+enum Part {
+	Text(String),
+	Path,
+	Host,
+	Date,
+	Token(usize),
+	ContentLength,
+	WsKey,
+	WsAccept,
+}
+
+pub struct Header {
+	parts: Vec<Part>,
+	hosts: Vec<String>,
+	chunked: bool,
+}
+
+impl Header {
+	// the preset if any, otherwise the file at path
+	// client is for which side of the preset
+	pub fn load(path: &str, preset: Option<Preset>, client: bool, hosts: Vec<String>) -> Self {
+		let s = match preset {
+			Some(p) => p.template(client),
+			None => match read_to_string(path) {
+				Ok(s) => s,
+				Err(e) => {
+					warn!("error reading from {path}: {e}, will fallback to use an empty header");
+					String::new()
+				}
+			},
+		};
+		Self::parse(&s, hosts)
+	}
+
+	pub fn parse(s: &str, hosts: Vec<String>) -> Self {
+		let mut parts = Vec::new();
+		let mut text = String::with_capacity(0x200);
+		let mut chunked = false;
+		for l in s.lines() {
+			let l = l.trim();
+			if l.is_empty() {
+				continue;
+			}
+			chunked |= is_chunked(l);
+			let mut l = l;
+			while let Some(start) = l.find('{')
+				&& let Some(len) = l[start..].find('}')
+			{
+				let part = match &l[start + 1..start + len] {
+					"path" => Part::Path,
+					"host" => Part::Host,
+					"date" => Part::Date,
+					"token" => Part::Token(32),
+					"content_length" => Part::ContentLength,
+					"ws_key" => Part::WsKey,
+					"ws_accept" => Part::WsAccept,
+					p => match p.strip_prefix("token:").map(str::parse) {
+						Some(Ok(n)) => Part::Token(n),
+						_ => {
+							warn!("unknown placeholder {{{p}}} in the fake header, kept as is");
+							text.push_str(&l[..start + len + 1]);
+							l = &l[start + len + 1..];
+							continue;
+						}
+					},
+				};
+				text.push_str(&l[..start]);
+				parts.push(Part::Text(std::mem::take(&mut text)));
+				parts.push(part);
+				l = &l[start + len + 1..];
+			}
+			text.push_str(l);
+			text.push_str("\r\n");
+		}
+		// still a double CRLF if there's nothing
+		if parts.is_empty() && text.is_empty() {
+			text.push_str("\r\n");
+		}
+		text.push_str("\r\n");
+		parts.push(Part::Text(text));
+		Self {
+			parts,
+			hosts,
+			chunked,
+		}
+	}
+
+	// len is of the message following it
+	// ws_key is the one in the request, for responses
+	pub fn render(&self, buf: &mut BytesMut, len: usize, ws_key: Option<&str>) {
+		for p in &self.parts {
+			match p {
+				Part::Text(s) => buf.put_slice(s.as_bytes()),
+				Part::Path => put_path(buf),
+				Part::Host => {
+					if !self.hosts.is_empty() {
+						let h = &self.hosts[rand::random_range(0..self.hosts.len())];
+						buf.put_slice(h.as_bytes());
+					}
+				}
+				Part::Date => {
+					buf.put_slice(httpdate::fmt_http_date(SystemTime::now()).as_bytes());
+				}
+				Part::Token(n) => put_token(buf, *n),
+				Part::ContentLength => buf.put_slice(len.to_string().as_bytes()),
+				Part::WsKey => buf.put_slice(
+					BASE64_STANDARD
+						.encode(rand::random::<[u8; 16]>())
+						.as_bytes(),
+				),
+				Part::WsAccept => {
+					// the client can't tell anyway if there's none
+					let key = match ws_key {
+						Some(k) => k.to_owned(),
+						None => BASE64_STANDARD.encode(rand::random::<[u8; 16]>()),
+					};
+					buf.put_slice(ws_accept(&key).as_bytes());
+				}
+			}
+		}
+		if self.chunked {
+			buf.put_slice(format!("{len:x}\r\n").as_bytes());
+		}
+	}
+}
 
-pub fn get_fake_header(path: &str) -> Vec<u8> {
-	let Ok(s) = read_to_string(path).inspect_err(|e| {
-		warn!("error reading from {path}: {e}, will fallback to use an empty header")
-	}) else {
-		return Vec::from(b"\r\n\r\n");
-	};
-	let mut res = String::with_capacity(0x200);
-	for l in s.lines() {
-		let l = l.trim();
-		if l.is_empty() {
-			continue;
+// just the end of the header
+impl Default for Header {
+	fn default() -> Self {
+		Self::parse("", vec![])
+	}
+}
+
+// whether the message after it has a chunk size line
+pub fn chunked(header: &[u8]) -> bool {
+	str::from_utf8(header).is_ok_and(|h| h.lines().any(is_chunked))
+}
+
+fn is_chunked(line: &str) -> bool {
+	line.split_once(':').is_some_and(|(k, v)| {
+		k.trim().eq_ignore_ascii_case("transfer-encoding")
+			&& v.trim().eq_ignore_ascii_case("chunked")
+	})
+}
+
+// Sec-WebSocket-Key in the header, if any
+pub fn ws_key(header: &[u8]) -> Option<String> {
+	field(str::from_utf8(header).ok()?, "sec-websocket-key").map(str::to_owned)
+}
+
+// value of the first header field named so, case insensitive
+pub fn field<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+	header.lines().find_map(|l| {
+		let (k, v) = l.split_once(':')?;
+		k.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+	})
+}
+
+pub fn ws_accept(key: &str) -> String {
+	let mut h = Sha1::new();
+	h.update(key.as_bytes());
+	h.update(WS_GUID.as_bytes());
+	BASE64_STANDARD.encode(h.finalize())
+}
+
+fn put_path(buf: &mut BytesMut) {
+	for _ in 0..rand::random_range(1..4) {
+		buf.put_u8(b'/');
+		for _ in 0..rand::random_range(3..11) {
+			// lowercase letters and digits
+			buf.put_u8(ALNUM[rand::random_range(26..ALNUM.len())]);
 		}
-		res.push_str(l);
-		res.push_str("\r\n");
 	}
-	res.push_str("\r\n");
-	res.into_bytes()
+}
+
+fn put_token(buf: &mut BytesMut, n: usize) {
+	for _ in 0..n {
+		buf.put_u8(ALNUM[rand::random_range(0..ALNUM.len())]);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let h = Header::parse(
+			"POST {path} HTTP/1.1\nHost: {host}\nCookie: a={token:8}; b={token}\nContent-Length: {content_length}\nX: {unknown}\n",
+			vec!["example.com".into()],
+		);
+		let mut buf = BytesMut::new();
+		h.render(&mut buf, 123, None);
+		let s = str::from_utf8(&buf).unwrap();
+		let lines: Vec<&str> = s.split("\r\n").collect();
+		assert!(lines[0].starts_with("POST /") && lines[0].ends_with(" HTTP/1.1"));
+		assert_eq!("Host: example.com", lines[1]);
+		assert_eq!("Cookie: a=".len() + 8 + "; b=".len() + 32, lines[2].len());
+		assert_eq!("Content-Length: 123", lines[3]);
+		assert_eq!("X: {unknown}", lines[4]);
+		assert!(s.ends_with("\r\n\r\n"));
+
+		// different every time
+		let mut other = BytesMut::new();
+		h.render(&mut other, 123, None);
+		assert_ne!(buf, other);
+	}
+
+	#[test]
+	fn test_presets() {
+		let h = Header::load(
+			"",
+			Some(Preset::ChunkedPost),
+			true,
+			vec!["example.com".into()],
+		);
+		let mut buf = BytesMut::new();
+		h.render(&mut buf, 0x1a3, None);
+		assert!(buf.ends_with(b"\r\n\r\n1a3\r\n"));
+		assert!(chunked(&buf));
+
+		let h = Header::load(
+			"",
+			Some(Preset::Websocket),
+			true,
+			vec!["example.com".into()],
+		);
+		let mut req = BytesMut::new();
+		h.render(&mut req, 0, None);
+		assert!(!chunked(&req));
+		let key = ws_key(&req).unwrap();
+		let h = Header::load("", Some(Preset::Websocket), false, vec![]);
+		let mut resp = BytesMut::new();
+		h.render(&mut resp, 0, Some(&key));
+		let accept = format!("Sec-WebSocket-Accept: {}\r\n", ws_accept(&key));
+		assert!(str::from_utf8(&resp).unwrap().contains(&accept));
+
+		// from RFC 6455
+		assert_eq!(
+			"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+			ws_accept("dGhlIHNhbXBsZSBub25jZQ==")
+		);
+	}
 }
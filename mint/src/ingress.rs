@@ -0,0 +1,174 @@
+// how apps reach the client
+// transparent modes take the destination from the socket, for iptables, Linux only
+//	REDIRECT: a regular listener, the destination is getsockopt(SO_ORIGINAL_DST)
+//	TPROXY: a listener with IP_TRANSPARENT, the destination is the local address,
+//	which takes CAP_NET_ADMIN
+// the client's own connections to servers must be excluded from the rules, like by uid
+// or forwards, a listener of their own for each, with a fixed destination
+
+use std::net::{IpAddr, SocketAddr};
+
+use log::*;
+use socks5::Dst;
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Ingress {
+	// SOCKS5, or HTTP CONNECT
+	Socks,
+	Redirect,
+	Tproxy,
+}
+
+// to be called on the listener before accepting
+pub fn init(l: &TcpListener, ingress: Ingress) -> Option<()> {
+	if cfg!(not(any(target_os = "linux", target_os = "android"))) && ingress != Ingress::Socks {
+		error!("redirect and tproxy ingress are only supported on linux");
+		return None;
+	}
+	if ingress != Ingress::Tproxy {
+		return Some(());
+	}
+	set_transparent(l)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_transparent(l: &TcpListener) -> Option<()> {
+	let s = socket2::SockRef::from(l);
+	let addr = l
+		.local_addr()
+		.inspect_err(|e| error!("error getting local address: {e}"))
+		.ok()?;
+	if addr.is_ipv4() {
+		s.set_ip_transparent_v4(true)
+	} else {
+		s.set_ip_transparent_v6(true)
+	}
+	.inspect_err(|e| error!("error setting IP_TRANSPARENT, CAP_NET_ADMIN is needed: {e}"))
+	.ok()
+}
+
+// init refuses it on other platforms
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_transparent(_: &TcpListener) -> Option<()> {
+	None
+}
+
+// where the app was connecting to, None if the connection wasn't redirected
+// listen is the listener's address, connections right to it aren't
+pub fn original_dst(s: &TcpStream, ingress: Ingress, listen: SocketAddr) -> Option<SocketAddr> {
+	let local = s
+		.local_addr()
+		.inspect_err(|e| error!("error getting local address: {e}"))
+		.ok()?;
+	let dst = match ingress {
+		Ingress::Socks => return None,
+		Ingress::Tproxy => local,
+		Ingress::Redirect => redirected_dst(s, local)?,
+	};
+	if dst.port() == listen.port() && (listen.ip().is_unspecified() || dst.ip() == listen.ip()) {
+		debug!("not redirected, to {dst}");
+		return None;
+	}
+	Some(dst)
+}
+
+// like ssh -L, [listen_host:]listen_port:host:port, listening on localhost without listen_host
+pub struct Forward {
+	pub listen: String,
+	host: String,
+	port: u16,
+}
+
+impl Forward {
+	pub fn dst(&self) -> Dst<'static> {
+		match self.host.parse::<IpAddr>() {
+			Ok(ip) => (ip, self.port).into(),
+			Err(_) => (self.host.clone(), self.port).into(),
+		}
+	}
+}
+
+pub fn parse_forward(s: &str) -> Option<Forward> {
+	let invalid = || error!("invalid forward, expecting [listen_host:]listen_port:host:port: {s}");
+	let Some((rest, port)) = s
+		.rsplit_once(':')
+		.and_then(|(r, p)| Some((r, p.parse::<u16>().ok()?)))
+	else {
+		invalid();
+		return None;
+	};
+	// an IPv6 address is in brackets
+	let (listen, host) = match rest.strip_suffix(']').and_then(|r| r.rsplit_once(":[")) {
+		Some((listen, host)) => (listen, host),
+		None => rest.rsplit_once(':').unwrap_or(("", "")),
+	};
+	if host.is_empty() || listen.is_empty() {
+		invalid();
+		return None;
+	}
+	let listen = if listen.parse::<u16>().is_ok() {
+		format!("127.0.0.1:{listen}")
+	} else {
+		listen.to_owned()
+	};
+	Some(Forward {
+		listen,
+		host: host.to_owned(),
+		port,
+	})
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn redirected_dst(s: &TcpStream, local: SocketAddr) -> Option<SocketAddr> {
+	let s = socket2::SockRef::from(s);
+	if local.is_ipv4() {
+		s.original_dst_v4()
+	} else {
+		s.original_dst_v6()
+	}
+	.inspect_err(|e| debug!("error getting the original destination: {e}"))
+	.ok()?
+	.as_socket()
+}
+
+// init refuses it on other platforms
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn redirected_dst(_: &TcpStream, _: SocketAddr) -> Option<SocketAddr> {
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_forward() {
+		let f = parse_forward("5432:db.internal:5432").unwrap();
+		assert_eq!("127.0.0.1:5432", f.listen);
+		assert_eq!("db.internal:5432", f.dst().to_string());
+		let f = parse_forward("0.0.0.0:8443:192.0.2.1:443").unwrap();
+		assert_eq!("0.0.0.0:8443", f.listen);
+		assert_eq!("192.0.2.1:443", f.dst().to_string());
+		let f = parse_forward("[::1]:2222:[2001:db8::1]:22").unwrap();
+		assert_eq!("[::1]:2222", f.listen);
+		assert_eq!("2001:db8::1", f.host);
+
+		assert!(parse_forward("db.internal:5432").is_none());
+		assert!(parse_forward("5432:db.internal:x").is_none());
+	}
+
+	#[tokio::test]
+	async fn test_not_redirected() {
+		let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = l.local_addr().unwrap();
+		let (c, s) = tokio::join!(TcpStream::connect(addr), l.accept());
+		let (_c, (s, _)) = (c.unwrap(), s.unwrap());
+		assert!(original_dst(&s, Ingress::Socks, addr).is_none());
+		assert!(original_dst(&s, Ingress::Tproxy, addr).is_none());
+		assert!(original_dst(&s, Ingress::Redirect, addr).is_none());
+		// as if it was tproxied from elsewhere
+		let listen = SocketAddr::new([0, 0, 0, 0].into(), addr.port().wrapping_add(1));
+		assert_eq!(Some(addr), original_dst(&s, Ingress::Tproxy, listen));
+	}
+}
@@ -1,22 +1,472 @@
+use std::{
+	rc::Rc,
+	time::{Duration, UNIX_EPOCH},
+};
+
 use log::*;
 
+use aes_gcm::Aes256Gcm;
 use base64::prelude::{BASE64_STANDARD_NO_PAD as BASE64, Engine as _};
-use chacha20poly1305::aead::{Generate as _, Key, KeyInit, KeySizeUser};
+use chacha20poly1305::{
+	ChaCha20Poly1305, XChaCha20Poly1305,
+	aead::{AeadCore, AeadInOut, Generate as _, Key, KeyInit, KeySizeUser},
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::replay::now;
+
+// the name is bound into handshakes
+pub trait Cipher: KeyInit + AeadCore + AeadInOut + Clone + 'static {
+	const NAME: &'static str;
+}
+
+impl Cipher for ChaCha20Poly1305 {
+	const NAME: &'static str = "chacha20-poly1305";
+}
+
+impl Cipher for XChaCha20Poly1305 {
+	const NAME: &'static str = "xchacha20-poly1305";
+}
+
+// fast with AES-NI
+impl Cipher for Aes256Gcm {
+	const NAME: &'static str = "aes-256-gcm";
+}
+
+#[cfg(test)]
+impl Cipher for chacha20poly1305::ChaCha8Poly1305 {
+	const NAME: &'static str = "chacha8-poly1305";
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum CipherKind {
+	#[value(name = "chacha20-poly1305")]
+	ChaCha20Poly1305,
+	#[value(name = "xchacha20-poly1305")]
+	XChaCha20Poly1305,
+	#[value(name = "aes-256-gcm")]
+	Aes256Gcm,
+}
 
 pub fn gen_psk<C: KeySizeUser>() -> String {
 	let key = Key::<C>::generate();
 	BASE64.encode(key.as_slice())
 }
 
-pub fn init_cipher<C: KeyInit>(key_path: &str) -> Option<C> {
-	let key = std::fs::read(key_path)
-		.inspect_err(|e| error!("failed to read \"{key_path}\": {e}"))
+// the key is kept, to derive session keys from
+#[derive(Clone)]
+pub struct Psk<C: KeySizeUser> {
+	key: Key<C>,
+	pub cipher: C,
+	pub name: &'static str,
+	// unix time it's not valid after, None means never
+	pub not_after: Option<u64>,
+}
+
+impl<C: KeyInit> Psk<C> {
+	pub fn new(key: Key<C>) -> Self
+	where
+		C: Cipher,
+	{
+		let cipher = C::new(&key);
+		Self {
+			key,
+			cipher,
+			name: C::NAME,
+			not_after: None,
+		}
+	}
+
+	pub fn is_expired(&self, now: u64) -> bool {
+		self.not_after.is_some_and(|t| t <= now)
+	}
+
+	// HKDF-SHA256 with the PSK as input key material
+	pub fn derive(&self, salt: &[u8], info: &[u8]) -> C {
+		let mut key = Key::<C>::default();
+		// can't fail, keys are way shorter than 255 * 32
+		Hkdf::<Sha256>::new(Some(salt), &self.key)
+			.expand(info, &mut key)
+			.unwrap();
+		C::new(&key)
+	}
+}
+
+// a key in a PSK file
+#[cfg_attr(test, derive(Debug, PartialEq))]
+struct Entry {
+	id: u32,
+	// unix time
+	created: u64,
+	// unix time, None means never
+	not_after: Option<u64>,
+	// in base64
+	key: String,
+}
+
+const PSK_FILE_HEADER: &str = "# id created not-after key, in unix time, not-after 0 means never\n";
+
+// either a bare key, or lines of id, created, not-after and key,
+// more than one while rotating
+fn parse_entries(s: &str) -> Option<Vec<Entry>> {
+	let lines: Vec<_> = s
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with('#'))
+		.collect();
+	if let [key] = lines[..]
+		&& !key.contains(char::is_whitespace)
+	{
+		return Some(vec![Entry {
+			id: 0,
+			created: 0,
+			not_after: None,
+			key: key.to_owned(),
+		}]);
+	}
+	lines
+		.iter()
+		.map(|l| {
+			let [id, created, not_after, key] = l.split_whitespace().collect::<Vec<_>>()[..] else {
+				error!("invalid line, expecting an id, created, not-after and a key: \"{l}\"");
+				return None;
+			};
+			let (Ok(id), Ok(created), Ok(not_after)) =
+				(id.parse(), created.parse(), not_after.parse::<u64>())
+			else {
+				error!("invalid id or time: \"{l}\"");
+				return None;
+			};
+			Some(Entry {
+				id,
+				created,
+				not_after: (not_after != 0).then_some(not_after),
+				key: key.to_owned(),
+			})
+		})
+		.collect()
+}
+
+fn format_entries(entries: &[Entry]) -> String {
+	let mut s = PSK_FILE_HEADER.to_owned();
+	for e in entries {
+		s += &format!(
+			"{} {} {} {}\n",
+			e.id,
+			e.created,
+			e.not_after.unwrap_or(0),
+			e.key
+		);
+	}
+	s
+}
+
+fn fmt_time(t: u64) -> String {
+	httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(t))
+}
+
+// ones not expired, newest first
+fn valid_psks<C: Cipher>(s: &str, path: &str) -> Option<Vec<Psk<C>>> {
+	let now = now();
+	let mut entries = parse_entries(s)?;
+	entries.sort_by_key(|e| std::cmp::Reverse(e.created));
+	let mut psks = Vec::with_capacity(entries.len());
+	for e in entries {
+		let mut psk = parse_psk(e.key.as_bytes())?;
+		psk.not_after = e.not_after;
+		if psk.is_expired(now) {
+			warn!("key {} in \"{path}\" expired", e.id);
+			continue;
+		}
+		if let Some(t) = e.not_after {
+			debug!("key {} in \"{path}\" expires at {}", e.id, fmt_time(t));
+		}
+		psks.push(psk);
+	}
+	if psks.is_empty() {
+		error!("no valid keys in \"{path}\"");
+		return None;
+	}
+	Some(psks)
+}
+
+fn load_psks<C: Cipher>(path: &str) -> Option<Vec<Psk<C>>> {
+	let s = std::fs::read_to_string(path)
+		.inspect_err(|e| error!("failed to read \"{path}\": {e}"))
 		.ok()?;
+	valid_psks(&s, path)
+}
+
+// the newest one
+pub fn init_psk<C: Cipher>(key_path: &str) -> Option<Psk<C>> {
+	load_psks(key_path)?.into_iter().next()
+}
+
+// a new key, valid for lifetime, None means forever
+pub fn gen_psk_file<C: KeySizeUser>(lifetime: Option<Duration>) -> String {
+	let created = now();
+	format_entries(&[Entry {
+		id: 1,
+		created,
+		not_after: lifetime.map(|l| created + l.as_secs()),
+		key: gen_psk::<C>(),
+	}])
+}
+
+// adds a new key as the newest, old ones are valid for overlap more at most, expired ones are dropped
+// the file is replaced atomically, created if it doesn't exist
+pub fn rotate_psk<C: KeySizeUser>(
+	path: &str,
+	lifetime: Option<Duration>,
+	overlap: Duration,
+) -> Option<()> {
+	let s = match std::fs::read_to_string(path) {
+		Ok(s) => s,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+		Err(e) => {
+			error!("failed to read \"{path}\": {e}");
+			return None;
+		}
+	};
+	let entries = rotate_entries(parse_entries(&s)?, now(), lifetime, overlap, gen_psk::<C>());
+	let tmp = format!("{path}.tmp");
+	write_private(&tmp, format_entries(&entries).as_bytes())
+		.inspect_err(|e| error!("failed to write \"{tmp}\": {e}"))
+		.ok()?;
+	std::fs::rename(&tmp, path)
+		.inspect_err(|e| error!("failed to rename \"{tmp}\" to \"{path}\": {e}"))
+		.ok()?;
+	let new = entries.last()?;
+	info!(
+		"key {} added to \"{path}\", {} keys in it",
+		new.id,
+		entries.len()
+	);
+	Some(())
+}
+
+fn rotate_entries(
+	mut entries: Vec<Entry>,
+	now: u64,
+	lifetime: Option<Duration>,
+	overlap: Duration,
+	key: String,
+) -> Vec<Entry> {
+	entries.retain(|e| e.not_after.is_none_or(|t| t > now));
+	let until = now + overlap.as_secs();
+	for e in &mut entries {
+		e.not_after = Some(e.not_after.map_or(until, |t| t.min(until)));
+	}
+	let id = entries.iter().map(|e| e.id + 1).max().unwrap_or(1);
+	entries.push(Entry {
+		id,
+		created: now,
+		not_after: lifetime.map(|l| now + l.as_secs()),
+		key,
+	});
+	entries
+}
+
+// readable by the owner only
+fn write_private(path: &str, data: &[u8]) -> std::io::Result<()> {
+	use std::io::Write;
+
+	let mut o = std::fs::OpenOptions::new();
+	o.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut o, 0o600);
+	let mut f = o.open(path)?;
+	f.write_all(data)?;
+	f.sync_all()
+}
+
+pub fn parse_psk<C: Cipher>(key: &[u8]) -> Option<Psk<C>> {
 	let key = BASE64
-		.decode((&key as &[u8]).trim_ascii())
+		.decode(key.trim_ascii())
 		.inspect_err(|e| error!("failed to decode base64: {e}"))
 		.ok()?;
-	C::new_from_slice(&key)
-		.inspect_err(|e| error!("failed to create cipher: {e}"))
-		.ok()
+	let key = Key::<C>::try_from(&key as &[u8])
+		.inspect_err(|_| error!("invalid key length: {}", key.len()))
+		.ok()?;
+	Some(Psk::new(key))
+}
+
+// a named key on the server
+pub struct User<C: KeySizeUser> {
+	pub name: Rc<str>,
+	pub psk: Psk<C>,
+}
+
+// path is either
+// a directory, with a PSK file for each name, named after the file
+// or a PSK file, with keys named "default", or lines of names and keys
+// a name has more than one key while rotating
+pub fn init_users<C: Cipher>(path: &str) -> Option<Vec<User<C>>> {
+	let meta = std::fs::metadata(path)
+		.inspect_err(|e| error!("failed to read \"{path}\": {e}"))
+		.ok()?;
+	let mut users = Vec::new();
+	if meta.is_dir() {
+		let dir = std::fs::read_dir(path)
+			.inspect_err(|e| error!("failed to read \"{path}\": {e}"))
+			.ok()?;
+		for e in dir {
+			let e = e
+				.inspect_err(|e| error!("failed to read \"{path}\": {e}"))
+				.ok()?;
+			let name = e.file_name().to_string_lossy().into_owned();
+			// like editor backups
+			if name.starts_with('.') || !e.path().is_file() {
+				continue;
+			}
+			let name: Rc<str> = name.into();
+			for psk in load_psks(&e.path().to_string_lossy())? {
+				users.push(User {
+					name: name.clone(),
+					psk,
+				});
+			}
+		}
+	} else {
+		let s = std::fs::read_to_string(path)
+			.inspect_err(|e| error!("failed to read \"{path}\": {e}"))
+			.ok()?;
+		users = parse_users(&s, path)?;
+	}
+	if users.is_empty() {
+		error!("no keys in \"{path}\"");
+		return None;
+	}
+	// stable, keys of a name stay newest first
+	users.sort_by(|a, b| a.name.cmp(&b.name));
+	info!("{} keys loaded from \"{path}\"", users.len());
+	Some(users)
+}
+
+fn parse_users<C: Cipher>(s: &str, path: &str) -> Option<Vec<User<C>>> {
+	let lines: Vec<_> = s
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with('#'))
+		.collect();
+	// a bare key, or lines of id, created, not-after and key
+	if lines
+		.first()
+		.is_some_and(|l| matches!(l.split_whitespace().count(), 1 | 4))
+	{
+		let name: Rc<str> = "default".into();
+		return Some(
+			valid_psks(s, path)?
+				.into_iter()
+				.map(|psk| User {
+					name: name.clone(),
+					psk,
+				})
+				.collect(),
+		);
+	}
+	let mut names: Vec<_> = lines
+		.iter()
+		.filter_map(|l| l.split_whitespace().next())
+		.collect();
+	names.sort();
+	if let Some(w) = names.windows(2).find(|w| w[0] == w[1]) {
+		error!("duplicate key name \"{}\"", w[0]);
+		return None;
+	}
+	lines
+		.iter()
+		.map(|l| {
+			let Some((name, key)) = l.split_once(char::is_whitespace) else {
+				error!("invalid line, expecting a name and a key: \"{l}\"");
+				return None;
+			};
+			Some(User {
+				name: name.into(),
+				psk: parse_psk(key.as_bytes())?,
+			})
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use chacha20poly1305::ChaCha8Poly1305 as C;
+
+	use super::*;
+
+	#[test]
+	fn test_users() {
+		let (a, b) = (gen_psk::<C>(), gen_psk::<C>());
+		let users = parse_users::<C>(&format!("{a}\n"), "").unwrap();
+		assert_eq!("default", &*users[0].name);
+
+		let users = parse_users::<C>(&format!("# comment\nalice {a}\n\nbob\t{b}\n"), "").unwrap();
+		assert_eq!(
+			vec!["alice", "bob"],
+			users.iter().map(|u| &*u.name).collect::<Vec<_>>()
+		);
+		assert_eq!(BASE64.encode(users[1].psk.key), b);
+
+		assert!(parse_users::<C>(&format!("alice {a}\nbob\n"), "").is_none());
+		assert!(parse_users::<C>(&format!("alice {a}\nalice {b}\n"), "").is_none());
+
+		// rotating, both keys of default, newest first, the expired one dropped
+		let now = now();
+		let users = parse_users::<C>(
+			&format!(
+				"1 100 {} {a}\n2 200 0 {b}\n0 0 100 {}\n",
+				now + 60,
+				gen_psk::<C>()
+			),
+			"",
+		)
+		.unwrap();
+		assert_eq!(2, users.len());
+		assert_eq!(BASE64.encode(users[0].psk.key), b);
+		assert_eq!(Some(now + 60), users[1].psk.not_after);
+	}
+
+	#[test]
+	fn test_rotate() {
+		let (a, b) = (gen_psk::<C>(), gen_psk::<C>());
+		let day = Duration::from_secs(86400);
+		let entries = parse_entries(&a).unwrap();
+		assert_eq!(
+			vec![Entry {
+				id: 0,
+				created: 0,
+				not_after: None,
+				key: a.clone()
+			}],
+			entries
+		);
+
+		// the old key is kept for overlap
+		let entries = rotate_entries(entries, 1000, Some(day * 90), day, b.clone());
+		let s = format_entries(&entries);
+		assert_eq!(
+			format!(
+				"{PSK_FILE_HEADER}0 0 {} {a}\n1 1000 {} {b}\n",
+				1000 + 86400,
+				1000 + 86400 * 90
+			),
+			s
+		);
+		assert_eq!(entries, parse_entries(&s).unwrap());
+
+		// expired ones dropped, and not extended
+		let entries = rotate_entries(entries, 1000 + 86400, None, day * 7, a.clone());
+		assert_eq!(
+			vec![(1, Some(1000 + 86400 * 8)), (2, None)],
+			entries
+				.iter()
+				.map(|e| (e.id, e.not_after))
+				.collect::<Vec<_>>()
+		);
+
+		assert!(parse_entries(&format!("1 x 0 {a}\n")).is_none());
+		assert!(parse_entries(&format!("1 0 {a}\n")).is_none());
+	}
 }
@@ -1,23 +1,56 @@
 use std::{
+	cell::{Cell, RefCell},
 	net::{IpAddr, SocketAddr},
 	rc::Rc,
 	str::FromStr,
+	time::Duration,
 };
 
 use clap::{Parser, Subcommand};
 use log::*;
+use socket2::{SockRef, TcpKeepalive};
 
-use chacha20poly1305::{ChaCha20Poly1305 as Cipher, aead::bytes::BytesMut};
-use tokio::net::{TcpStream, lookup_host};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+	ChaCha20Poly1305, XChaCha20Poly1305,
+	aead::bytes::{BufMut, BytesMut},
+};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::{TcpListener, TcpStream, UdpSocket},
+};
+use tokio_rustls::{TlsConnector, rustls::pki_types::ServerName};
 
-use socks5::{Addr, Dst, connect, listen, parse_bind, parse_dns_conf};
+use socks5::{
+	AclArgs, Addr, Cmd, Dst, Limiter, Limits, Metered, Resolver, Scope, Session, Stats, Timeouts,
+	accept, copy_bidirectional, listen, parse_bind, parse_dns_conf, relay, serve_stats, timeout,
+	udp_reply, udp_reply_failure,
+};
 
+mod balance;
 mod fake;
+mod ingress;
 mod key;
+mod mux;
+mod prefixed;
 mod proto;
+mod replay;
+mod reverse;
+mod tls;
+mod udp;
+mod ws;
 
+use balance::{Balancer, Conn, Entry, Server, Strategy, load_servers};
+use fake::{Header, Preset};
+use ingress::{Forward, Ingress};
 use key::*;
+use mux::Pool;
+use prefixed::Prefixed;
 use proto::*;
+use replay::Replay;
+use reverse::Services;
+use tls::Stream;
+use ws::{Transport, Upgrade};
 
 #[derive(Parser)]
 #[command(version = env!("REV"))]
@@ -29,43 +62,269 @@ struct Args {
 #[derive(Subcommand)]
 enum Cmds {
 	#[command(alias = "s")]
-	Server {
-		/// PSK file path
-		#[arg(short = 'k', env, default_value = "conf/psk")]
-		psk: String,
+	Server(ServerArgs),
 
-		#[arg(short, env, default_value = "127.0.0.1:8080")]
-		listen: String,
+	#[command(alias = "c")]
+	Client(ClientArgs),
 
-		/// bind address for upstream connections
-		#[arg(short, env, default_value = "")]
-		bind: String,
+	/// register services with the server, reached through it
+	#[command(alias = "a")]
+	Agent(AgentArgs),
 
-		#[arg(short, env, default_value = "")]
-		dns: String,
+	/// generate PSK
+	GenPSK(GenArgs),
 
-		#[arg(short, env, default_value = "conf/fake-resp.txt")]
-		fake_header: String,
-	},
+	/// add a new key to a PSK file, keeping the old ones for a while
+	#[command(name = "rotate-psk")]
+	RotatePSK(RotateArgs),
+}
 
-	#[command(alias = "c")]
-	Client {
-		/// PSK file path
-		#[arg(short = 'k', env, default_value = "conf/psk")]
-		psk: String,
+#[derive(clap::Args)]
+struct GenArgs {
+	#[arg(long, env, value_enum, default_value_t = CipherKind::ChaCha20Poly1305)]
+	cipher: CipherKind,
 
-		#[arg(short, env, default_value = "127.0.0.1:1080")]
-		listen: String,
+	/// days the key is valid for, 0 means forever
+	#[arg(long, env, default_value_t = 0)]
+	lifetime: u64,
 
-		#[arg(short, env, default_value = "127.0.0.1:8080")]
-		server: String,
+	/// just the key, like for the lines of a servers file, without an id or times
+	#[arg(long, env)]
+	bare: bool,
+}
 
-		#[arg(short, env, default_value = "conf/fake-req.txt")]
-		fake_header: String,
-	},
+#[derive(clap::Args)]
+struct RotateArgs {
+	/// PSK file path, replaced atomically, created if it doesn't exist
+	#[arg(short = 'k', env, default_value = "conf/psk")]
+	psk: String,
 
-	/// generate PSK
-	GenPSK,
+	#[arg(long, env, value_enum, default_value_t = CipherKind::ChaCha20Poly1305)]
+	cipher: CipherKind,
+
+	/// days the new key is valid for, 0 means forever
+	#[arg(long, env, default_value_t = 0)]
+	lifetime: u64,
+
+	/// days the old keys are still valid for, so both sides can be updated in the meantime
+	#[arg(long, env, default_value_t = 7)]
+	overlap: u64,
+}
+
+#[derive(clap::Args)]
+struct ServerArgs {
+	/// PSK file path, or a file or directory of named keys, reloaded on SIGHUP
+	#[arg(short = 'k', env, default_value = "conf/psk")]
+	psk: String,
+
+	/// must be the same on both sides
+	#[arg(long, env, value_enum, default_value_t = CipherKind::ChaCha20Poly1305)]
+	cipher: CipherKind,
+
+	#[arg(short, env, default_value = "127.0.0.1:8080")]
+	listen: String,
+
+	/// bind address or interface for upstream connections
+	#[arg(short, env, default_value = "")]
+	bind: String,
+
+	/// fwmark for upstream connections, 0 means none
+	#[arg(long, env, default_value_t = 0)]
+	fwmark: u32,
+
+	#[arg(short, env, default_value = "")]
+	dns: String,
+
+	/// template of the fake HTTP response header
+	#[arg(short, env, default_value = "conf/fake-resp.txt")]
+	fake_header: String,
+
+	/// built-in fake header, instead of the template
+	#[arg(long, env, value_enum)]
+	fake_preset: Option<Preset>,
+
+	/// refuse clients not in full mode
+	#[arg(long, env)]
+	require_full: bool,
+
+	/// path of WebSocket upgrades, the stream is carried in WebSocket frames,
+	/// like behind a reverse proxy, empty means disabled
+	#[arg(long, env, default_value = "")]
+	ws: String,
+
+	/// TLS certificate chain in PEM, connections are in TLS, empty means disabled
+	#[arg(long, env, default_value = "")]
+	tls_cert: String,
+
+	/// private key of the TLS certificate in PEM
+	#[arg(long, env, default_value = "")]
+	tls_key: String,
+
+	/// ALPN protocols in TLS, comma separated
+	#[arg(long, env, value_delimiter = ',', default_value = "http/1.1")]
+	tls_alpn: Vec<String>,
+
+	/// padding of encrypted records
+	#[arg(long, env, value_enum, default_value_t = Padding::None)]
+	padding: Padding,
+
+	/// max clock difference from clients in seconds, handshakes are remembered for twice as long
+	#[arg(long, env, default_value_t = 120)]
+	replay_window: u64,
+
+	/// where invalid handshakes are proxied to, like a local web server, empty means they're dropped
+	#[arg(long, env, default_value = "")]
+	fallback: String,
+
+	/// UDP associations end after this many seconds without datagrams either way,
+	/// so do NAT entries
+	#[arg(long, env, default_value_t = 60)]
+	udp_timeout: u64,
+
+	/// services of agents exposed on local ports, like ssh=127.0.0.1:2222, comma separated,
+	/// clients reach them as <name>.mint either way
+	#[arg(long, env, value_delimiter = ',')]
+	expose: Vec<String>,
+
+	#[command(flatten)]
+	timeouts: Timeouts,
+
+	#[command(flatten)]
+	limits: Limits,
+
+	// destinations in private ranges are denied by default
+	#[command(flatten)]
+	acl: AclArgs,
+
+	/// listen address of the stats endpoint, empty means disabled
+	#[arg(long, env, default_value = "")]
+	stats: String,
+}
+
+#[derive(clap::Args)]
+struct ClientArgs {
+	/// PSK file path, reloaded on SIGHUP
+	#[arg(short = 'k', env, default_value = "conf/psk")]
+	psk: String,
+
+	/// must be the same on both sides
+	#[arg(long, env, value_enum, default_value_t = CipherKind::ChaCha20Poly1305)]
+	cipher: CipherKind,
+
+	#[arg(short, env, default_value = "127.0.0.1:1080")]
+	listen: String,
+
+	/// how apps reach the client, socks takes SOCKS5 and HTTP CONNECT,
+	/// redirect and tproxy are for iptables REDIRECT and TPROXY, Linux only
+	#[arg(long, env, value_enum, default_value_t = Ingress::Socks)]
+	ingress: Ingress,
+
+	/// forwards like ssh -L, [listen_host:]listen_port:host:port, each listening on its own,
+	/// with connections going to host:port, repeatable or comma separated
+	#[arg(short = 'L', long, env, value_delimiter = ',')]
+	forward: Vec<String>,
+
+	#[arg(short, env, default_value = "127.0.0.1:8080")]
+	server: String,
+
+	/// file of servers, a name, an address, a key or a PSK file and optionally a fake header template
+	/// on each line, instead of -s and -k, empty means disabled, keys are reloaded on SIGHUP
+	#[arg(long, env, default_value = "")]
+	servers: String,
+
+	/// DNS servers for the server's host, comma separated, the system resolver if empty
+	#[arg(short, env, default_value = "")]
+	dns: String,
+
+	/// how often the server's host is resolved again in seconds,
+	/// it's also resolved again after a few connect failures in a row, 0 means only then
+	#[arg(long, env, default_value_t = 300)]
+	resolve_interval: u64,
+
+	/// how servers are picked for each connection, failing over to the others
+	#[arg(long, env, value_enum, default_value_t = Strategy::RoundRobin)]
+	balance: Strategy,
+
+	/// how often servers are probed for latency in seconds, with --balance latency
+	#[arg(long, env, default_value_t = 30)]
+	probe_interval: u64,
+
+	/// template of the fake HTTP request header
+	#[arg(short, env, default_value = "conf/fake-req.txt")]
+	fake_header: String,
+
+	/// built-in fake header, instead of the template, must be the same on both sides
+	#[arg(long, env, value_enum)]
+	fake_preset: Option<Preset>,
+
+	/// hosts for {host} in the fake header and the WebSocket upgrade, comma separated,
+	/// the server's by default
+	#[arg(long, env, value_delimiter = ',')]
+	fake_host: Vec<String>,
+
+	/// encrypt the whole stream, instead of just the first few records
+	#[arg(long, env)]
+	full: bool,
+
+	/// path of the WebSocket upgrade, the stream is carried in WebSocket frames,
+	/// must be the same on both sides, empty means disabled
+	#[arg(long, env, default_value = "")]
+	ws: String,
+
+	/// connect to the server in TLS
+	#[arg(long, env)]
+	tls: bool,
+
+	/// server name in TLS, the server's host by default
+	#[arg(long, env, default_value = "")]
+	tls_sni: String,
+
+	/// ALPN protocols in TLS, comma separated
+	#[arg(long, env, value_delimiter = ',', default_value = "http/1.1")]
+	tls_alpn: Vec<String>,
+
+	/// SHA-256 of the server certificate in base64, trusted instead of public CAs,
+	/// the server logs it on start
+	#[arg(long, env, default_value = "")]
+	tls_pin: String,
+
+	/// padding of encrypted records
+	#[arg(long, env, value_enum, default_value_t = Padding::None)]
+	padding: Padding,
+
+	/// multiplex over up to this many connections to the server, in full mode, 0 means disabled
+	#[arg(long, env, default_value_t = 0)]
+	mux: usize,
+
+	/// how long to wait for the first data from the client in milliseconds, at most while connecting,
+	/// so it's sent along with the handshake, 0 means disabled
+	#[arg(long, env, default_value_t = 50)]
+	early_data_wait: u64,
+
+	#[command(flatten)]
+	timeouts: Timeouts,
+
+	#[command(flatten)]
+	limits: Limits,
+
+	/// listen address of the stats endpoint, empty means disabled
+	#[arg(long, env, default_value = "")]
+	stats: String,
+}
+
+#[derive(clap::Args)]
+struct AgentArgs {
+	/// services to register, names and where they are, like ssh=127.0.0.1:22, comma separated
+	#[arg(long, env, value_delimiter = ',', required = true)]
+	service: Vec<String>,
+
+	/// seconds to wait before registering a service again, after it's disconnected
+	#[arg(long, env, default_value_t = 5)]
+	retry_interval: u64,
+
+	// the options for connecting to servers
+	#[command(flatten)]
+	client: ClientArgs,
 }
 
 #[cfg(debug_assertions)]
@@ -73,6 +332,26 @@ const LOG_LEVEL: &str = "debug";
 #[cfg(not(debug_assertions))]
 const LOG_LEVEL: &str = "info";
 
+// evaluates $e with $c as the chosen cipher type
+macro_rules! with_cipher {
+	($kind:expr, $c:ident => $e:expr) => {
+		match $kind {
+			CipherKind::ChaCha20Poly1305 => {
+				type $c = ChaCha20Poly1305;
+				$e
+			}
+			CipherKind::XChaCha20Poly1305 => {
+				type $c = XChaCha20Poly1305;
+				$e
+			}
+			CipherKind::Aes256Gcm => {
+				type $c = Aes256Gcm;
+				$e
+			}
+		}
+	};
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
 	let args = Args::parse();
@@ -80,59 +359,313 @@ async fn main() {
 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(LOG_LEVEL)).init();
 
 	match &args.cmd {
-		Cmds::Server {
-			psk,
-			listen,
-			bind,
-			dns,
-			fake_header,
-		} => {
-			ls_run(server(psk, listen, bind, dns, fake_header)).await;
+		Cmds::Server(args) => {
+			with_cipher!(args.cipher, C => ls_run(server::<C>(args)).await);
+		}
+		Cmds::Client(args) => {
+			with_cipher!(args.cipher, C => ls_run(client::<C>(args)).await);
 		}
-		Cmds::Client {
-			psk,
-			listen,
-			server,
-			fake_header,
-		} => {
-			ls_run(client(psk, listen, server, fake_header)).await;
+		Cmds::Agent(args) => {
+			with_cipher!(args.client.cipher, C => ls_run(agent::<C>(args)).await);
+		}
+		Cmds::GenPSK(args) => {
+			if args.bare {
+				with_cipher!(args.cipher, C => println!("{}", gen_psk::<C>()));
+			} else {
+				let lifetime = days(args.lifetime);
+				with_cipher!(args.cipher, C => print!("{}", gen_psk_file::<C>(lifetime)));
+			}
 		}
-		Cmds::GenPSK => {
-			println!("{}", gen_psk::<Cipher>());
+		Cmds::RotatePSK(args) => {
+			let (lifetime, overlap) =
+				(days(args.lifetime), Duration::from_secs(args.overlap * DAY));
+			let rotated =
+				with_cipher!(args.cipher, C => rotate_psk::<C>(&args.psk, lifetime, overlap));
+			// it's run by scripts too
+			if rotated.is_none() {
+				std::process::exit(1);
+			}
 		}
 	}
 }
 
+const DAY: u64 = 86400;
+
+// None for 0, forever
+fn days(d: u64) -> Option<Duration> {
+	(d > 0).then(|| Duration::from_secs(d * DAY))
+}
+
 // runs in local set
 async fn ls_run(f: impl Future) {
 	let ls = tokio::task::LocalSet::new();
 	ls.run_until(f).await;
 }
 
-async fn server(key: &str, l_addr: &str, bind: &str, dns: &str, fake_header: &str) -> Option<()> {
-	let fake_header = Rc::new(fake::get_fake_header(fake_header));
-	let cipher: Cipher = init_cipher(key)?;
+async fn server<C: Cipher>(args: &ServerArgs) -> Option<()> {
+	// the upgrade is the HTTP part with WebSocket
+	let fake_header = Rc::new(if args.ws.is_empty() {
+		Header::load(&args.fake_header, args.fake_preset, false, vec![])
+	} else {
+		Header::default()
+	});
+	let users = Rc::new(RefCell::new(Rc::new(init_users::<C>(&args.psk)?)));
+	#[cfg(unix)]
+	tokio::task::spawn_local(reload_users(args.psk.clone(), users.clone()));
 
-	let bind = parse_bind(bind)?;
+	let bind = parse_bind(&args.bind, args.fwmark)?;
 
-	let dns = parse_dns_conf(dns)?;
+	let dns = parse_dns_conf(&args.dns, bind.as_ref())?;
 
-	let l = listen(l_addr).await?;
+	let acl = Rc::new(args.acl.parse("private")?);
+
+	let timeouts = args.timeouts;
+	let require_full = args.require_full;
+	let padding = args.padding;
+	let replay = Rc::new(Replay::new(args.replay_window));
+	let fallback = Rc::new(args.fallback.clone());
+	let udp_timeout = Duration::from_secs(args.udp_timeout.max(1));
+	let ws = Rc::new(args.ws.clone());
+	let tls = if args.tls_cert.is_empty() {
+		None
+	} else {
+		Some(tls::acceptor(
+			&args.tls_cert,
+			&args.tls_key,
+			&args.tls_alpn,
+		)?)
+	};
+
+	let stats = Stats::new("mint");
+	if !args.stats.is_empty() {
+		tokio::task::spawn_local(serve_stats(args.stats.clone(), stats.clone()));
+	}
+
+	let l = listen(&args.listen).await?;
+	let limiter = Limiter::new(args.limits);
+
+	let services = Rc::new(Services::default());
+	for e in &args.expose {
+		let Some((name, addr)) = e.split_once('=') else {
+			error!("invalid service to expose, expecting name=address: {e}");
+			return None;
+		};
+		tokio::task::spawn_local(reverse::expose(
+			services.clone(),
+			name.to_owned(),
+			addr.to_owned(),
+			limiter.clone(),
+			timeouts,
+			stats.clone(),
+		));
+	}
 
-	while let Ok((mut s, r_addr)) = l.accept().await {
+	loop {
+		let (s, r_addr, permit) = accept(&l, &limiter).await;
+		if !acl.check_src(r_addr.ip()) {
+			info!("connection from {r_addr} denied");
+			continue;
+		}
 		let _ = s.set_nodelay(true);
-		let cipher = cipher.clone();
+		// so reloading doesn't affect existing connections
+		let users = users.borrow().clone();
+		let bind = bind.clone();
 		let dns = dns.clone();
+		let acl = acl.clone();
 		let fake_header = fake_header.clone();
+		let replay = replay.clone();
+		let fallback = fallback.clone();
+		let ws = ws.clone();
+		let tls = tls.clone();
+		let stats = stats.clone();
+		let services = services.clone();
+		let limiter = limiter.clone();
+		let mut sess = stats.session();
+		sess.with(Scope::Client, r_addr.ip());
 		tokio::task::spawn_local(async move {
+			let _permit = permit;
+			let s = match tls {
+				None => Stream::Plain(s),
+				Some(tls) => {
+					let Some(s) = timeout(
+						format_args!("TLS handshake from {r_addr}"),
+						timeouts.handshake(),
+						async {
+							tls.accept(s)
+								.await
+								.inspect_err(|e| debug!("TLS handshake error from {r_addr}: {e}"))
+								.ok()
+						},
+					)
+					.await
+					else {
+						sess.fail();
+						return;
+					};
+					Stream::Tls(Box::new(s.into()))
+				}
+			};
 			let mut buf = BytesMut::with_capacity(0x600);
-			let Some((host, port)) =
-				server_handshake(&mut s, &cipher, &mut buf, &fake_header).await
+			let mut s = if ws.is_empty() {
+				Transport::Raw(s)
+			} else {
+				match timeout(
+					format_args!("WebSocket upgrade from {r_addr}"),
+					timeouts.handshake(),
+					ws::accept(s, &ws, &mut buf),
+				)
+				.await
+				{
+					Some(Upgrade::Ok(s)) => Transport::Ws(s),
+					Some(Upgrade::Invalid(s)) => {
+						sess.fail();
+						if !fallback.is_empty() {
+							debug!("invalid WebSocket upgrade from {r_addr}, to fallback");
+							fallback_to(s, &buf, &fallback, timeouts).await;
+						}
+						return;
+					}
+					None => {
+						sess.fail();
+						return;
+					}
+				}
+			};
+			let Some(accepted) = timeout(
+				format_args!("handshake from {r_addr}"),
+				timeouts.handshake(),
+				server_handshake(
+					&mut s,
+					&users,
+					&mut buf,
+					&fake_header,
+					require_full,
+					&replay,
+				),
+			)
+			.await
 			else {
+				sess.fail();
+				// like a slow probe, a web server would answer it one way or another
+				if let Transport::Raw(s) = s
+					&& !fallback.is_empty()
+					&& !buf.is_empty()
+				{
+					debug!("incomplete handshake from {r_addr}, to fallback");
+					fallback_to(s, &buf, &fallback, timeouts).await;
+				}
 				return;
 			};
-			drop(buf);
-			let dst = if let Ok(addr) = IpAddr::from_str(&host) {
+			let req = match accepted {
+				Accept::Ok(req) => req,
+				Accept::Invalid => {
+					sess.fail();
+					// what's read is in frames after an upgrade, it's only for the raw stream
+					if let Transport::Raw(s) = s
+						&& !fallback.is_empty()
+					{
+						debug!("invalid handshake from {r_addr}, to fallback");
+						fallback_to(s, &buf, &fallback, timeouts).await;
+					}
+					return;
+				}
+			};
+			if req.reverse() {
+				if req.host.is_empty() {
+					info!("{r_addr}: service with no name, user {}", req.user);
+					sess.fail();
+					return;
+				}
+				let name = &req.host;
+				let claimed = services.claim(name, &req.user);
+				if !claimed {
+					warn!(
+						"{r_addr}: service {name} belongs to another user, refused for user {}",
+						req.user
+					);
+				}
+				let Some(session) = reply(&mut s, &fake_header, &req, claimed).await else {
+					sess.fail();
+					return;
+				};
+				let session = session.padding(padding);
+				info!("{r_addr}: service {name} registered, user {}", req.user);
+				let (mux, mut incoming) = mux::over(session, Prefixed::new(s, buf), false);
+				mux.keepalive(MUX_KEEPALIVE);
+				services.register(name, mux.clone());
+				// streams are only opened by the server, this is to tell when it ends
+				while incoming.recv().await.is_some() {}
+				services.unregister(name, &mux);
+				info!("{r_addr}: service {name} unregistered");
+				return;
+			}
+			if req.mux() {
+				let Some(session) = reply(&mut s, &fake_header, &req, true).await else {
+					sess.fail();
+					return;
+				};
+				let session = session.padding(padding);
+				info!("{r_addr}: mux, user {}", req.user);
+				let (_, mut incoming) = mux::over(session, Prefixed::new(s, buf), false);
+				while let Some((dst, stream)) = incoming.recv().await {
+					// counted like connections, dropping the stream closes it
+					let Some(permit) = limiter.admit(r_addr.ip()) else {
+						info!(
+							"{r_addr} -> {dst}, user {}, muxed, refused by limits",
+							req.user
+						);
+						continue;
+					};
+					let (bind, dns, acl) = (bind.clone(), dns.clone(), acl.clone());
+					let services = services.clone();
+					let user = req.user.clone();
+					let mut sess = stats.session();
+					sess.with(Scope::Client, r_addr.ip());
+					tokio::task::spawn_local(async move {
+						let _permit = permit;
+						info!("{r_addr} -> {dst}, user {user}, muxed");
+						sess.with(Scope::Destination, &dst.addr);
+						let Some(u) = timeout(
+							format_args!("connecting to {dst}"),
+							timeouts.connect(),
+							reverse::connect(&services, bind.as_ref(), dns, &acl, &dst),
+						)
+						.await
+						else {
+							sess.fail();
+							return;
+						};
+						if copy_bidirectional(u, Metered::new(stream, &sess), timeouts.idle())
+							.await
+							.is_none()
+						{
+							debug!("idle timeout or error: {r_addr} -> {dst}");
+						}
+					});
+				}
+				debug!("mux ended: {r_addr}");
+				return;
+			}
+			if req.udp() {
+				let Some(session) = reply(&mut s, &fake_header, &req, true).await else {
+					sess.fail();
+					return;
+				};
+				let session = session.padding(padding);
+				info!("{r_addr}: UDP, user {}", req.user);
+				let (plain, mut p) = tokio::io::duplex(0x10000);
+				let mut s = Prefixed::new(s, buf);
+				// the plain side, like on the client
+				let plain = Metered::new(plain, &sess);
+				tokio::join!(
+					duplex(session, &mut p, &mut s),
+					udp::serve(plain, bind, dns, acl.clone(), udp_timeout),
+				);
+				debug!("UDP ended: {r_addr}");
+				return;
+			}
+			let port = req.port;
+			let dst = if let Ok(addr) = IpAddr::from_str(&req.host) {
 				match addr {
 					IpAddr::V4(a) => Dst {
 						addr: Addr::V4(a),
@@ -145,83 +678,737 @@ async fn server(key: &str, l_addr: &str, bind: &str, dns: &str, fake_header: &st
 				}
 			} else {
 				Dst {
-					addr: Addr::DomainOwned(host),
+					addr: Addr::DomainOwned(req.host.clone()),
 					port,
 				}
 			};
-			info!("{r_addr} -> {dst}");
-			let Some(mut u) = connect(bind, dns, &dst).await else {
+			info!("{r_addr} -> {dst}, user {}", req.user);
+			sess.with(Scope::Destination, &dst.addr);
+			let u = async {
+				timeout(
+					format_args!("connecting to {dst}"),
+					timeouts.connect(),
+					async {
+						let mut u =
+							reverse::connect(&services, bind.as_ref(), dns, &acl, &dst).await?;
+						if let Some(early) = &req.early {
+							u.write_all(early)
+								.await
+								.inspect_err(|e| debug!("error writing early data to {dst}: {e}"))
+								.ok()?;
+							sess.count(early.len() as u64, 0);
+						}
+						Some(u)
+					},
+				)
+				.await
+			};
+			// with early data, the client is told whether it made it upstream
+			// otherwise, the reply is on its way while connecting
+			let (u, session) = if req.early.is_some() {
+				let u = u.await;
+				let session = reply(&mut s, &fake_header, &req, u.is_some()).await;
+				(u, session)
+			} else {
+				let Some(session) = reply(&mut s, &fake_header, &req, true).await else {
+					sess.fail();
+					return;
+				};
+				(u.await, Some(session))
+			};
+			let (Some(u), Some(session)) = (u, session) else {
+				sess.fail();
 				return;
 			};
-			let _ = u.set_nodelay(true);
-			duplex(&cipher, &mut u, &mut s).await;
+			let session = session.padding(padding);
+			// early data from the client
+			let s = Prefixed::new(s, buf);
+			// the plain side, like on the client
+			if relay(
+				Metered::upstream(u, &sess),
+				s,
+				timeouts.idle(),
+				async |u, s| duplex(session, u, s).await,
+			)
+			.await
+			.is_none()
+			{
+				info!("idle timeout: {r_addr} -> {dst}");
+			}
 			debug!("connection ended: {r_addr} -> {dst}");
 		});
 	}
+}
 
+// on SIGHUP, existing connections keep the keys they were accepted with
+#[cfg(unix)]
+async fn reload_users<C: Cipher>(path: String, users: Rc<RefCell<Rc<Vec<User<C>>>>>) -> Option<()> {
+	use tokio::signal::unix::{SignalKind, signal};
+
+	let mut hup = signal(SignalKind::hangup())
+		.inspect_err(|e| error!("failed to listen for SIGHUP: {e}"))
+		.ok()?;
+	while hup.recv().await.is_some() {
+		info!("reloading keys from \"{path}\"");
+		match init_users(&path) {
+			Some(u) => *users.borrow_mut() = Rc::new(u),
+			None => error!("failed to reload keys, the old ones are kept"),
+		}
+	}
 	Some(())
 }
 
-async fn client(key: &str, l_addr: &str, upstream_str: &str, fake_header: &str) -> Option<()> {
-	let fake_header = Rc::new(fake::get_fake_header(fake_header));
-	let cipher: Cipher = init_cipher(key)?;
-
-	let upstream: Vec<SocketAddr> = lookup_host(upstream_str)
+// so to those without the PSK, it looks like whatever the fallback is
+// in plain text, after TLS if it's in TLS
+async fn fallback_to<T: AsyncRead + AsyncWrite + Unpin>(
+	s: T,
+	read: &[u8],
+	addr: &str,
+	timeouts: Timeouts,
+) -> Option<()> {
+	let mut f = timeout(
+		format_args!("connecting to fallback {addr}"),
+		timeouts.connect(),
+		async {
+			TcpStream::connect(addr)
+				.await
+				.inspect_err(|e| error!("error connecting to fallback {addr}: {e}"))
+				.ok()
+		},
+	)
+	.await?;
+	let _ = f.set_nodelay(true);
+	f.write_all(read)
 		.await
-		.inspect_err(|e| error!("failed to lookup {upstream_str}: {e}"))
-		.ok()?
+		.inspect_err(|e| debug!("error writing to fallback: {e}"))
+		.ok()?;
+	copy_bidirectional(s, f, timeouts.idle()).await?;
+	Some(())
+}
+
+async fn client<C: Cipher>(args: &ClientArgs) -> Option<()> {
+	let balancer = init_balancer::<C>(args).await?;
+	let timeouts = args.timeouts;
+	let mode = if args.full { Mode::Full } else { Mode::Plain };
+	let padding = args.padding;
+	let early_wait = Duration::from_millis(args.early_data_wait);
+
+	// a mux connection isn't counted as connections to the server
+	let pool = (args.mux > 0).then(|| {
+		let balancer = balancer.clone();
+		Rc::new(Pool::new(args.mux, async move || {
+			// not used by the server
+			let dst = Dst {
+				addr: Addr::Domain(""),
+				port: 0,
+			};
+			let (server, u, session) =
+				connect_server(&balancer, timeouts, &dst, Mode::Mux, async {
+					BytesMut::new()
+				})
+				.await?;
+			debug!("mux connected to server {}", server.name);
+			let (mux, _) = mux::over(session.padding(padding), u, true);
+			mux.keepalive(MUX_KEEPALIVE);
+			Some(mux)
+		}))
+	});
+
+	let stats = Stats::new("mint");
+	if !args.stats.is_empty() {
+		tokio::task::spawn_local(serve_stats(args.stats.clone(), stats.clone()));
+	}
+
+	let mut forwards = Vec::with_capacity(args.forward.len());
+	for f in &args.forward {
+		let f = ingress::parse_forward(f)?;
+		let l = listen(&f.listen).await?;
+		forwards.push((l, Rc::new(f)));
+	}
+	let l = listen(&args.listen).await?;
+	let ingress = args.ingress;
+	ingress::init(&l, ingress)?;
+	let limiter = Limiter::new(args.limits);
+
+	// connections to l, or to the listener of a forward
+	let serve = |l: TcpListener, forward: Option<Rc<Forward>>| {
+		let (balancer, pool) = (balancer.clone(), pool.clone());
+		let (stats, limiter) = (stats.clone(), limiter.clone());
+		async move {
+			let l_addr = l.local_addr().ok()?;
+			loop {
+				let (mut s, r_addr, permit) = accept(&l, &limiter).await;
+				let _ = s.set_nodelay(true);
+				let balancer = balancer.clone();
+				let pool = pool.clone();
+				let forward = forward.clone();
+				let mut sess = stats.session();
+				sess.with(Scope::Client, r_addr.ip());
+				tokio::task::spawn_local(async move {
+					let _permit = permit;
+					let cmd = match (&forward, ingress) {
+						(Some(f), _) => Some(Cmd::Connect(f.dst())),
+						(None, Ingress::Socks) => {
+							timeout(
+								format_args!("socks5 handshake from {r_addr}"),
+								timeouts.handshake(),
+								socks5::server_handshake_udp(&mut s),
+							)
+							.await
+						}
+						(None, _) => ingress::original_dst(&s, ingress, l_addr)
+							.map(|a| Cmd::Connect(Dst::from((a.ip(), a.port())))),
+					};
+					let Some(cmd) = cmd else {
+						sess.fail();
+						return;
+					};
+					let dst = match cmd {
+						Cmd::Connect(dst) => dst,
+						Cmd::UdpAssociate(_) => {
+							info!("{r_addr}: UDP associate");
+							if udp_associate(s, &balancer, padding, timeouts, &mut sess)
+								.await
+								.is_none()
+							{
+								sess.fail();
+							}
+							debug!("UDP association ended: {r_addr}");
+							return;
+						}
+					};
+					info!("{r_addr} -> {dst}");
+					sess.with(Scope::Destination, &dst.addr);
+					if let Some(pool) = pool {
+						let Some(u) = pool.open(&dst).await else {
+							sess.fail();
+							return;
+						};
+						if copy_bidirectional(Metered::new(s, &sess), u, timeouts.idle())
+							.await
+							.is_none()
+						{
+							info!("idle timeout or error: {r_addr} -> {dst}");
+						}
+						debug!("connection ended: {r_addr} -> {dst}");
+						return;
+					}
+					// the first data from the client, read while connecting
+					let early = async {
+						let mut early = BytesMut::new();
+						if !early_wait.is_zero() {
+							let mut limited = (&mut early).limit(MAX_EARLY);
+							let _ =
+								tokio::time::timeout(early_wait, s.read_buf(&mut limited)).await;
+							sess.count(early.len() as u64, 0);
+						}
+						early
+					};
+					let Some((server, u, session)) =
+						connect_server(&balancer, timeouts, &dst, mode, early).await
+					else {
+						sess.fail();
+						return;
+					};
+					sess.with(Scope::Upstream, &server.name);
+					let s = Metered::new(s, &sess);
+					let session = session.padding(padding);
+					// early data from the server is in u
+					if relay(s, u, timeouts.idle(), async |s, u| {
+						duplex(session, s, u).await
+					})
+					.await
+					.is_none()
+					{
+						info!("idle timeout: {r_addr} -> {dst}");
+					}
+					debug!("connection ended: {r_addr} -> {dst}");
+				});
+			}
+		}
+	};
+	for (l, f) in forwards {
+		info!("forwarding {} to {}", f.listen, f.dst());
+		tokio::task::spawn_local(serve(l, Some(f)));
+	}
+	serve(l, None).await
+}
+
+// services stay registered, each in a session of its own, registered again when it ends
+async fn agent<C: Cipher>(args: &AgentArgs) -> Option<()> {
+	let mut services = Vec::with_capacity(args.service.len());
+	for s in &args.service {
+		let Some((name, target)) = s.split_once('=').filter(|(n, _)| !n.is_empty()) else {
+			error!("invalid service, expecting name=address: {s}");
+			return None;
+		};
+		services.push((name.to_owned(), target.to_owned()));
+	}
+	let balancer = init_balancer::<C>(&args.client).await?;
+	let timeouts = args.client.timeouts;
+	let padding = args.client.padding;
+	let retry = Duration::from_secs(args.retry_interval);
+	let tasks: Vec<_> = services
+		.into_iter()
+		.map(|(name, target)| {
+			let balancer = balancer.clone();
+			tokio::task::spawn_local(async move {
+				let dst = Dst {
+					addr: Addr::Domain(&name),
+					port: 0,
+				};
+				loop {
+					if let Some((server, u, session)) =
+						connect_server(&balancer, timeouts, &dst, Mode::Reverse, async {
+							BytesMut::new()
+						})
+						.await
+					{
+						info!("service {name} registered with server {}", server.name);
+						let (mux, incoming) = mux::over(session.padding(padding), u, true);
+						mux.keepalive(MUX_KEEPALIVE);
+						reverse::forward(incoming, &target, timeouts).await;
+						info!("service {name} disconnected from server {}", server.name);
+					} else {
+						warn!(
+							"service {name} not registered, retry in {}s",
+							retry.as_secs()
+						);
+					}
+					tokio::time::sleep(retry).await;
+				}
+			})
+		})
 		.collect();
-	if upstream.is_empty() {
-		error!("lookup {upstream_str} yields no result");
-		return None;
+	for t in tasks {
+		let _ = t.await;
+	}
+	Some(())
+}
+
+// the server, or those in the servers file
+fn load_entries<C: Cipher>(psk: &str, server: &str, servers: &str) -> Option<Vec<Entry<C>>> {
+	if servers.is_empty() {
+		Some(vec![Entry {
+			name: server.to_owned(),
+			addr: server.to_owned(),
+			psk: init_psk::<C>(psk)?,
+			fake_header: None,
+		}])
+	} else {
+		load_servers(servers)
 	}
-	info!(
-		"server addr: {}",
-		&upstream
-			.iter()
-			.map(SocketAddr::to_string)
-			.reduce(|a, b| a + ", " + &b)
-			.unwrap()
+}
+
+// on SIGHUP, just the keys, like after a rotation, other changes take a restart
+// existing connections keep the keys they were made with
+#[cfg(unix)]
+async fn reload_psks<C: Cipher>(
+	psk: String,
+	server: String,
+	servers: String,
+	balancer: Rc<Balancer<C>>,
+) -> Option<()> {
+	use tokio::signal::unix::{SignalKind, signal};
+
+	let mut hup = signal(SignalKind::hangup())
+		.inspect_err(|e| error!("failed to listen for SIGHUP: {e}"))
+		.ok()?;
+	while hup.recv().await.is_some() {
+		let path = if servers.is_empty() { &psk } else { &servers };
+		info!("reloading keys from \"{path}\"");
+		let Some(entries) = load_entries::<C>(&psk, &server, &servers) else {
+			error!("failed to reload keys, the old ones are kept");
+			continue;
+		};
+		for e in entries {
+			if !balancer.set_psk(&e.name, e.psk) {
+				warn!("server {} is new, it takes a restart", e.name);
+			}
+		}
+	}
+	Some(())
+}
+
+async fn init_balancer<C: Cipher>(args: &ClientArgs) -> Option<Rc<Balancer<C>>> {
+	let entries = load_entries(&args.psk, &args.server, &args.servers)?;
+	let tls = if args.tls {
+		Some(tls::connector(&args.tls_alpn, &args.tls_pin)?)
+	} else {
+		None
+	};
+	let dns = parse_dns_conf(&args.dns, None)?;
+	let mut servers = Vec::with_capacity(entries.len());
+	for e in entries {
+		let upstream = init_upstream(args, &e.addr, tls.clone(), dns.clone()).await?;
+		// the upgrade is the HTTP part with WebSocket
+		let fake_header = if !args.ws.is_empty() {
+			Header::default()
+		} else if let Some(path) = &e.fake_header {
+			Header::load(path, None, true, upstream.hosts.clone())
+		} else {
+			Header::load(
+				&args.fake_header,
+				args.fake_preset,
+				true,
+				upstream.hosts.clone(),
+			)
+		};
+		servers.push(Server::new(e.name, upstream, e.psk, fake_header));
+	}
+	let balancer = Rc::new(Balancer::new(servers, args.balance));
+	balancer.probe(
+		Duration::from_secs(args.probe_interval.max(1)),
+		args.timeouts.connect(),
 	);
-	let upstream = Rc::new(upstream);
+	if args.resolve_interval > 0 {
+		balancer.resolve(Duration::from_secs(args.resolve_interval));
+	}
+	#[cfg(unix)]
+	tokio::task::spawn_local(reload_psks(
+		args.psk.clone(),
+		args.server.clone(),
+		args.servers.clone(),
+		balancer.clone(),
+	));
+	Some(balancer)
+}
 
-	let l = listen(l_addr).await?;
+// to the servers in the order picked, failing over to the next until a handshake goes through,
+// but not once early data is sent
+// early data is read while connecting to the first
+async fn connect_server<C: Cipher>(
+	balancer: &Balancer<C>,
+	timeouts: Timeouts,
+	dst: &Dst<'_>,
+	mode: Mode,
+	read_early: impl Future<Output = BytesMut>,
+) -> Option<(
+	Conn<C>,
+	Prefixed<Transport<Stream<TcpStream>>>,
+	proto::Session<C>,
+)> {
+	let mut read_early = Some(read_early);
+	let mut early = BytesMut::new();
+	for picked in balancer.pick() {
+		// from the start, so connections made meanwhile pick by it too
+		let server = picked.conn();
+		let u = match read_early.take() {
+			Some(r) => {
+				let connect = connect_upstream(&server.upstream, timeouts);
+				tokio::pin!(connect);
+				// not waited for once connected, the read is dropped before it takes anything
+				tokio::select! {
+					e = r => {
+						early = e;
+						connect.await
+					}
+					u = &mut connect => u,
+				}
+			}
+			None => connect_upstream(&server.upstream, timeouts).await,
+		};
+		let Some(mut u) = u else {
+			server.fail();
+			if server.upstream.should_resolve() {
+				tokio::task::spawn_local(async move { picked.upstream.resolve().await });
+			}
+			continue;
+		};
+		let mut buf = BytesMut::with_capacity(0x600);
+		match timeout(
+			format_args!("handshake with server {}", server.name),
+			timeouts.handshake(),
+			client_handshake(
+				&mut u,
+				&server.psk(),
+				&mut buf,
+				dst,
+				&server.fake_header,
+				mode,
+				&early,
+			),
+		)
+		.await
+		{
+			Some(Connected::Ok(session)) => {
+				server.ok();
+				return Some((server, Prefixed::new(u, buf), session));
+			}
+			// it's the destination, not the server
+			Some(Connected::Failed) => return None,
+			None => {
+				server.fail();
+				// it may have reached the destination, so it's not sent again to another
+				if !early.is_empty() {
+					return None;
+				}
+			}
+		}
+	}
+	None
+}
 
-	while let Ok((mut s, r_addr)) = l.accept().await {
-		let _ = s.set_nodelay(true);
-		let fake_header = fake_header.clone();
-		let cipher = cipher.clone();
-		let upstream = upstream.clone();
-		tokio::task::spawn_local(async move {
-			let mut buf = BytesMut::with_capacity(0x600);
-			let Some(dst) = socks5::server_handshake(&mut s).await else {
-				return;
+// datagrams in a session of their own, not muxed
+// ends when the SOCKS5 connection s closes
+async fn udp_associate<C: Cipher>(
+	mut s: TcpStream,
+	balancer: &Balancer<C>,
+	padding: Padding,
+	timeouts: Timeouts,
+	sess: &mut Session,
+) -> Option<()> {
+	// on the address the client reached us at, it's what's in the reply
+	let local = s.local_addr().ok()?;
+	let app = s.peer_addr().ok()?.ip();
+	let socket = UdpSocket::bind((local.ip(), 0))
+		.await
+		.inspect_err(|e| error!("error binding UDP socket: {e}"))
+		.ok();
+	let Some(socket) = socket else {
+		udp_reply_failure(&mut s).await;
+		return None;
+	};
+	// not used by the server
+	let dst = Dst {
+		addr: Addr::Domain(""),
+		port: 0,
+	};
+	// the app is told, instead of just being disconnected
+	let Some((server, mut u, session)) =
+		connect_server(balancer, timeouts, &dst, Mode::Udp, async {
+			BytesMut::new()
+		})
+		.await
+	else {
+		udp_reply_failure(&mut s).await;
+		return None;
+	};
+	sess.with(Scope::Upstream, &server.name);
+	udp_reply(&mut s, socket.local_addr().ok()?).await?;
+	let (plain, mut p) = tokio::io::duplex(0x10000);
+	tokio::join!(
+		duplex(session.padding(padding), &mut p, &mut u),
+		udp::client(socket, app, &mut s, plain, sess),
+	);
+	Some(())
+}
+
+// addr is host:port
+async fn init_upstream(
+	args: &ClientArgs,
+	addr: &str,
+	tls: Option<TlsConnector>,
+	dns: Option<Resolver>,
+) -> Option<Upstream> {
+	let Some((host, _)) = addr
+		.rsplit_once(':')
+		.filter(|(_, p)| p.parse::<u16>().is_ok())
+	else {
+		error!("invalid server address, expecting host:port: {addr}");
+		return None;
+	};
+	let hosts = match &args.fake_host[..] {
+		[] => vec![host.to_owned()],
+		h => h.to_vec(),
+	};
+	let tls = match tls {
+		None => None,
+		Some(tls) => {
+			let sni = match args.tls_sni.as_str() {
+				"" => host.trim_matches(['[', ']']),
+				sni => sni,
 			};
-			info!("{r_addr} -> {dst}");
-			let Ok(mut u) = TcpStream::connect(&upstream as &[SocketAddr])
-				.await
-				.inspect_err(|e| error!("error connecting to upstream: {e}"))
-			else {
-				return;
+			let Ok(sni) = ServerName::try_from(sni.to_owned()) else {
+				error!("invalid TLS server name: {sni}");
+				return None;
 			};
-			let _ = u.set_nodelay(true);
-			let Some(()) = client_handshake(
-				&mut u,
-				&cipher,
-				&mut buf,
-				&dst.addr.to_string(),
-				dst.port,
-				&fake_header,
-			)
+			Some((tls, sni))
+		}
+	};
+	let upstream = Upstream {
+		addr: addr.to_owned(),
+		addrs: RefCell::default(),
+		dns,
+		failures: Cell::new(0),
+		tls,
+		ws: args.ws.clone(),
+		hosts,
+	};
+	upstream.resolve().await?;
+	Some(upstream)
+}
+
+const KEEPALIVE: Duration = Duration::from_secs(60);
+// muxes that are kept around are pinged, so a dead one isn't used on
+const MUX_KEEPALIVE: Duration = Duration::from_secs(30);
+
+// re-resolved after this many connect failures in a row, it may have moved
+const RESOLVE_AFTER_FAILURES: u32 = 3;
+
+// how connections to the server are made
+struct Upstream {
+	// host:port, as given
+	addr: String,
+	// resolved from addr, again now and then
+	addrs: RefCell<Vec<SocketAddr>>,
+	// the system resolver if None
+	dns: Option<Resolver>,
+	// connect failures in a row
+	failures: Cell<u32>,
+	tls: Option<(TlsConnector, ServerName<'static>)>,
+	// path of the WebSocket upgrade, empty means disabled
+	ws: String,
+	// for the WebSocket upgrade
+	hosts: Vec<String>,
+}
+
+impl Upstream {
+	// the addresses are kept if it fails
+	async fn resolve(&self) -> Option<()> {
+		let (host, port) = self.addr.rsplit_once(':')?;
+		let port = port.parse().ok()?;
+		let mut addrs =
+			socks5::resolve(None, self.dns.clone(), host.trim_matches(['[', ']']), port).await?;
+		// the order may change every time
+		addrs.sort();
+		if *self.addrs.borrow() != addrs {
+			info!(
+				"server addr of {}: {}",
+				self.addr,
+				addrs
+					.iter()
+					.map(SocketAddr::to_string)
+					.collect::<Vec<_>>()
+					.join(", ")
+			);
+			*self.addrs.borrow_mut() = addrs;
+		}
+		Some(())
+	}
+
+	// like an IP address, nothing to re-resolve
+	fn is_literal(&self) -> bool {
+		self.addr.parse::<SocketAddr>().is_ok()
+	}
+
+	// true once every few connect failures in a row
+	fn should_resolve(&self) -> bool {
+		if self.failures.get() < RESOLVE_AFTER_FAILURES || self.is_literal() {
+			return false;
+		}
+		self.failures.set(0);
+		true
+	}
+}
+
+async fn connect_upstream(
+	upstream: &Upstream,
+	timeouts: Timeouts,
+) -> Option<Transport<Stream<TcpStream>>> {
+	let addrs = upstream.addrs.borrow().clone();
+	let u = timeout("connecting to upstream", timeouts.connect(), async {
+		TcpStream::connect(&addrs[..])
 			.await
-			else {
-				return;
-			};
-			drop(buf);
-			duplex(&cipher, &mut s, &mut u).await;
-			debug!("connection ended: {r_addr} -> {dst}");
-		});
+			.inspect_err(|e| error!("error connecting to upstream: {e}"))
+			.ok()
+	})
+	.await;
+	let Some(u) = u else {
+		upstream.failures.set(upstream.failures.get() + 1);
+		return None;
+	};
+	upstream.failures.set(0);
+	let _ = u.set_nodelay(true);
+	// so dead ones are noticed, like of an idle agent behind NAT
+	let _ = SockRef::from(&u).set_tcp_keepalive(
+		&TcpKeepalive::new()
+			.with_time(KEEPALIVE)
+			.with_interval(KEEPALIVE),
+	);
+	let u = match &upstream.tls {
+		None => Stream::Plain(u),
+		Some((tls, sni)) => {
+			let u = timeout("TLS handshake with upstream", timeouts.handshake(), async {
+				tls.connect(sni.clone(), u)
+					.await
+					.inspect_err(|e| error!("TLS handshake error with upstream: {e}"))
+					.ok()
+			})
+			.await?;
+			Stream::Tls(Box::new(u.into()))
+		}
+	};
+	if upstream.ws.is_empty() {
+		return Some(Transport::Raw(u));
 	}
+	let u = timeout(
+		"WebSocket upgrade with upstream",
+		timeouts.handshake(),
+		ws::connect(u, &upstream.ws, &upstream.hosts),
+	)
+	.await?;
+	Some(Transport::Ws(u))
+}
 
-	Some(())
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn upstream(addr: &str) -> Upstream {
+		Upstream {
+			addr: addr.to_owned(),
+			addrs: RefCell::default(),
+			dns: None,
+			failures: Cell::new(0),
+			tls: None,
+			ws: String::new(),
+			hosts: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn test_is_literal() {
+		assert!(upstream("127.0.0.1:443").is_literal());
+		assert!(upstream("[::1]:443").is_literal());
+		assert!(!upstream("example.com:443").is_literal());
+		assert!(!upstream("localhost:443").is_literal());
+	}
+
+	#[test]
+	fn test_should_resolve() {
+		let u = upstream("example.com:443");
+		for _ in 0..RESOLVE_AFTER_FAILURES {
+			assert!(!u.should_resolve());
+			u.failures.set(u.failures.get() + 1);
+		}
+		assert!(u.should_resolve());
+		// counted again from there
+		assert_eq!(0, u.failures.get());
+		assert!(!u.should_resolve());
+
+		let u = upstream("127.0.0.1:443");
+		u.failures.set(RESOLVE_AFTER_FAILURES);
+		assert!(!u.should_resolve());
+	}
+
+	#[tokio::test]
+	async fn test_resolve() {
+		let old: SocketAddr = "192.0.2.1:443".parse().unwrap();
+
+		// replaced by a different answer
+		let u = upstream("127.0.0.1:443");
+		*u.addrs.borrow_mut() = vec![old];
+		assert!(u.resolve().await.is_some());
+		assert_eq!(
+			vec!["127.0.0.1:443".parse::<SocketAddr>().unwrap()],
+			*u.addrs.borrow()
+		);
+
+		// kept if it fails
+		let u = upstream("mint.invalid:443");
+		*u.addrs.borrow_mut() = vec![old];
+		assert!(u.resolve().await.is_none());
+		assert_eq!(vec![old], *u.addrs.borrow());
+	}
 }
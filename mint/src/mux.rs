@@ -0,0 +1,505 @@
+// many streams over one full mode session
+// frames: 4 bytes stream id, 1 byte type, 2 bytes length, payload
+// streams opened by the client have odd ids, by the server even
+// stream id 0 is the mux itself, for pings
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::HashMap,
+	rc::Rc,
+	time::Duration,
+};
+
+use chacha20poly1305::aead::{
+	AeadCore, AeadInOut,
+	bytes::{BufMut, Bytes, BytesMut},
+};
+use log::*;
+use socks5::{Dst, read_dst, write_dst};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, split},
+	select,
+	sync::{Notify, mpsc},
+	task::spawn_local,
+	time::Instant,
+};
+
+use crate::proto::{Session, duplex};
+
+// payload: the destination, in SOCKS5 format
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+// payload: 4 bytes of credit granted
+const WINDOW: u8 = 2;
+// the sender is closed
+const FIN: u8 = 3;
+// the stream is gone, in both directions
+const RST: u8 = 4;
+// of stream 0, answered with PONG and the same payload
+const PING: u8 = 5;
+const PONG: u8 = 6;
+
+const HEADER_LEN: usize = 7;
+const MAX_DATA: usize = 0x4000;
+
+// initial credit in each direction of a stream,
+// granted back once half of it is written out
+const WINDOW_SIZE: u32 = 0x40000;
+
+pub type Stream = DuplexStream;
+
+// streams opened by the peer
+pub type Incoming = mpsc::UnboundedReceiver<(Dst<'static>, Stream)>;
+
+pub struct Mux {
+	streams: RefCell<HashMap<u32, Entry>>,
+	// frames to be written, None once closed
+	tx: RefCell<Option<mpsc::UnboundedSender<Bytes>>>,
+	next_id: Cell<u32>,
+	// when the last frame from the peer was read
+	last_read: Cell<Instant>,
+	// to stop reading, when closed by keepalive
+	closing: Notify,
+}
+
+struct Entry {
+	shared: Rc<Shared>,
+	// data from the peer, None after FIN
+	data: Option<mpsc::UnboundedSender<Bytes>>,
+}
+
+#[derive(Default)]
+struct Shared {
+	// how much can be sent
+	credit: Cell<u32>,
+	// how much the peer can send
+	window: Cell<u32>,
+	reset: Cell<bool>,
+	// on credit or reset
+	notify: Notify,
+}
+
+// the session is run in its own task, encrypting frames
+pub fn over<C, T>(session: Session<C>, io: T, client: bool) -> (Rc<Mux>, Incoming)
+where
+	C: AeadCore + AeadInOut + 'static,
+	T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+	let (plain, mut p) = tokio::io::duplex(MAX_DATA * 4);
+	let mut io = io;
+	spawn_local(async move { duplex(session, &mut p, &mut io).await });
+	Mux::new(plain, client)
+}
+
+impl Mux {
+	pub fn new<T: AsyncRead + AsyncWrite + 'static>(io: T, client: bool) -> (Rc<Self>, Incoming) {
+		let (r, w) = split(io);
+		let (tx, frames) = mpsc::unbounded_channel();
+		let (incoming_tx, incoming) = mpsc::unbounded_channel();
+		let mux = Rc::new(Self {
+			streams: RefCell::default(),
+			tx: RefCell::new(Some(tx)),
+			next_id: Cell::new(if client { 1 } else { 2 }),
+			last_read: Cell::new(Instant::now()),
+			closing: Notify::new(),
+		});
+		spawn_local(write_frames(w, frames));
+		spawn_local(mux.clone().read_frames(r, incoming_tx));
+		(mux, incoming)
+	}
+
+	pub fn is_closed(&self) -> bool {
+		self.tx.borrow().is_none()
+	}
+
+	pub fn len(&self) -> usize {
+		self.streams.borrow().len()
+	}
+
+	// pings the peer every interval, closed if nothing is read from it in between,
+	// so a dead connection isn't used on
+	pub fn keepalive(self: &Rc<Self>, interval: Duration) {
+		let mux = Rc::downgrade(self);
+		spawn_local(async move {
+			let mut tick = tokio::time::interval(interval);
+			tick.tick().await;
+			loop {
+				let sent = Instant::now();
+				match mux.upgrade() {
+					Some(m) if !m.is_closed() => m.send(0, PING, b""),
+					_ => return,
+				}
+				tick.tick().await;
+				match mux.upgrade() {
+					Some(m) if m.last_read.get() < sent => {
+						debug!("mux: no reply to ping, closed");
+						m.close();
+						return;
+					}
+					Some(_) => {}
+					None => return,
+				}
+			}
+		});
+	}
+
+	// the remaining streams are reset
+	fn close(&self) {
+		self.closing.notify_one();
+		self.tx.borrow_mut().take();
+		for (_, e) in self.streams.borrow_mut().drain() {
+			e.shared.reset.set(true);
+			e.shared.notify.notify_waiters();
+		}
+	}
+
+	pub async fn open(self: &Rc<Self>, dst: &Dst<'_>) -> Option<Stream> {
+		if self.is_closed() {
+			return None;
+		}
+		let mut payload = Vec::with_capacity(0x100);
+		write_dst(&mut payload, dst).await?;
+		let id = self.next_id.get();
+		self.next_id.set(id + 2);
+		self.send(id, OPEN, &payload);
+		Some(self.stream(id))
+	}
+
+	fn stream(self: &Rc<Self>, id: u32) -> Stream {
+		let shared = Rc::new(Shared::default());
+		shared.credit.set(WINDOW_SIZE);
+		shared.window.set(WINDOW_SIZE);
+		let (data, rx) = mpsc::unbounded_channel();
+		self.streams.borrow_mut().insert(
+			id,
+			Entry {
+				shared: shared.clone(),
+				data: Some(data),
+			},
+		);
+		let (s, p) = tokio::io::duplex(MAX_DATA * 2);
+		spawn_local(self.clone().pump(id, shared, p, rx));
+		s
+	}
+
+	fn send(&self, id: u32, t: u8, payload: &[u8]) {
+		let Some(tx) = &*self.tx.borrow() else {
+			return;
+		};
+		let mut f = BytesMut::with_capacity(HEADER_LEN + payload.len());
+		f.put_u32(id);
+		f.put_u8(t);
+		f.put_u16(payload.len() as u16);
+		f.put_slice(payload);
+		let _ = tx.send(f.freeze());
+	}
+
+	// between a stream and the frames
+	async fn pump(
+		self: Rc<Self>,
+		id: u32,
+		shared: Rc<Shared>,
+		p: DuplexStream,
+		mut data: mpsc::UnboundedReceiver<Bytes>,
+	) {
+		let (mut r, mut w) = split(p);
+		let reset = |s: &Shared| {
+			s.reset.set(true);
+			s.notify.notify_waiters();
+		};
+		let up = async {
+			let mut buf = vec![0u8; MAX_DATA];
+			loop {
+				let notified = shared.notify.notified();
+				if shared.reset.get() {
+					return;
+				}
+				let credit = shared.credit.get() as usize;
+				if credit == 0 {
+					notified.await;
+					continue;
+				}
+				let n = select! {
+					r = r.read(&mut buf[..credit.min(MAX_DATA)]) => r,
+					_ = notified => continue,
+				};
+				match n {
+					Ok(0) => {
+						self.send(id, FIN, b"");
+						return;
+					}
+					Ok(n) => {
+						shared.credit.set(shared.credit.get() - n as u32);
+						self.send(id, DATA, &buf[..n]);
+					}
+					Err(e) => {
+						debug!("mux stream {id}: error reading: {e}");
+						self.send(id, RST, b"");
+						reset(&shared);
+						return;
+					}
+				}
+			}
+		};
+		let down = async {
+			let mut written = 0;
+			while let Some(d) = data.recv().await {
+				if let Err(e) = w.write_all(&d).await {
+					debug!("mux stream {id}: error writing: {e}");
+					self.send(id, RST, b"");
+					reset(&shared);
+					return;
+				}
+				written += d.len() as u32;
+				if written >= WINDOW_SIZE / 2 {
+					shared.window.set(shared.window.get() + written);
+					self.send(id, WINDOW, &written.to_be_bytes());
+					written = 0;
+				}
+			}
+			let _ = w.shutdown().await;
+		};
+		tokio::join!(up, down);
+		self.streams.borrow_mut().remove(&id);
+	}
+
+	async fn read_frames(
+		self: Rc<Self>,
+		mut r: impl AsyncRead + Unpin,
+		incoming: mpsc::UnboundedSender<(Dst<'static>, Stream)>,
+	) {
+		tokio::select! {
+			r = self.dispatch(&mut r, &incoming) => if r.is_none() {
+				debug!("mux closed");
+			},
+			_ = self.closing.notified() => {}
+		}
+		self.close();
+	}
+
+	async fn dispatch(
+		self: &Rc<Self>,
+		r: &mut (impl AsyncRead + Unpin),
+		incoming: &mpsc::UnboundedSender<(Dst<'static>, Stream)>,
+	) -> Option<()> {
+		let mut header = [0u8; HEADER_LEN];
+		let mut buf = vec![0u8; u16::MAX as usize];
+		// ids opened by the peer
+		let peer = (self.next_id.get() + 1) % 2;
+		loop {
+			r.read_exact(&mut header)
+				.await
+				.inspect_err(|e| debug!("mux error reading: {e}"))
+				.ok()?;
+			let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+			let t = header[4];
+			let len = u16::from_be_bytes(header[5..].try_into().unwrap()) as usize;
+			let payload = &mut buf[..len];
+			r.read_exact(payload)
+				.await
+				.inspect_err(|e| debug!("mux error reading: {e}"))
+				.ok()?;
+			self.last_read.set(Instant::now());
+
+			if id == 0 {
+				match t {
+					PING => self.send(0, PONG, payload),
+					PONG => {}
+					_ => {
+						error!("mux: invalid frame type {t} of the mux");
+						return None;
+					}
+				}
+				continue;
+			}
+			if t == OPEN {
+				if id % 2 != peer || self.streams.borrow().contains_key(&id) {
+					error!("mux: invalid stream id {id} to open");
+					return None;
+				}
+				let Some(dst) = read_dst(&mut &payload[..]).await else {
+					self.send(id, RST, b"");
+					continue;
+				};
+				let s = self.stream(id);
+				if incoming.send((dst, s)).is_err() {
+					debug!("mux: not accepting streams");
+				}
+				continue;
+			}
+
+			// it may be gone on this side already
+			let Some(shared) = self.streams.borrow().get(&id).map(|e| e.shared.clone()) else {
+				continue;
+			};
+			match t {
+				DATA => {
+					let Some(window) = shared.window.get().checked_sub(len as u32) else {
+						error!("mux stream {id}: exceeding the window");
+						return None;
+					};
+					shared.window.set(window);
+					if let Some(data) = self.streams.borrow().get(&id).and_then(|e| e.data.as_ref())
+					{
+						let _ = data.send(Bytes::copy_from_slice(payload));
+					}
+				}
+				WINDOW if len == 4 => {
+					let n = u32::from_be_bytes(payload[..].try_into().unwrap());
+					shared.credit.set(shared.credit.get().saturating_add(n));
+					shared.notify.notify_waiters();
+				}
+				FIN => {
+					if let Some(e) = self.streams.borrow_mut().get_mut(&id) {
+						e.data = None;
+					}
+				}
+				RST => {
+					self.streams.borrow_mut().remove(&id);
+					shared.reset.set(true);
+					shared.notify.notify_waiters();
+				}
+				_ => {
+					error!("mux stream {id}: invalid frame type {t}, length {len}");
+					return None;
+				}
+			}
+		}
+	}
+}
+
+async fn write_frames(mut w: impl AsyncWrite + Unpin, mut frames: mpsc::UnboundedReceiver<Bytes>) {
+	let mut buf = BytesMut::with_capacity(MAX_DATA * 2);
+	while let Some(f) = frames.recv().await {
+		// coalesced, so small frames don't end up in their own records
+		buf.put(f);
+		while buf.len() < MAX_DATA
+			&& let Ok(f) = frames.try_recv()
+		{
+			buf.put(f);
+		}
+		if let Err(e) = w.write_all(&buf).await {
+			debug!("mux error writing: {e}");
+			return;
+		}
+		buf.clear();
+	}
+	let _ = w.shutdown().await;
+}
+
+// client side, streams are spread over up to size muxes,
+// connected when needed, replaced when closed
+pub struct Pool<F> {
+	size: usize,
+	muxes: RefCell<Vec<Rc<Mux>>>,
+	connecting: Cell<usize>,
+	connect: F,
+}
+
+impl<F: AsyncFn() -> Option<Rc<Mux>>> Pool<F> {
+	pub fn new(size: usize, connect: F) -> Self {
+		Self {
+			size,
+			muxes: RefCell::default(),
+			connecting: Cell::new(0),
+			connect,
+		}
+	}
+
+	pub async fn open(&self, dst: &Dst<'_>) -> Option<Stream> {
+		self.muxes.borrow_mut().retain(|m| !m.is_closed());
+		let least = self.muxes.borrow().iter().min_by_key(|m| m.len()).cloned();
+		let mux = match least {
+			Some(m) if self.muxes.borrow().len() + self.connecting.get() >= self.size => m,
+			_ => self.connect().await?,
+		};
+		mux.open(dst).await
+	}
+
+	async fn connect(&self) -> Option<Rc<Mux>> {
+		self.connecting.set(self.connecting.get() + 1);
+		let mux = (self.connect)().await;
+		self.connecting.set(self.connecting.get() - 1);
+		let mux = mux?;
+		self.muxes.borrow_mut().push(mux.clone());
+		Some(mux)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::task::LocalSet;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_mux() {
+		LocalSet::new()
+			.run_until(async {
+				let (c, s) = tokio::io::duplex(0x1000);
+				let (c_mux, _) = Mux::new(c, true);
+				let (_s_mux, mut incoming) = Mux::new(s, false);
+
+				// more than the window, echoed back
+				let data: Vec<u8> = (0..WINDOW_SIZE * 3).map(|i| i as u8).collect();
+				let dst: Dst = ("example.com", 443).into();
+				let mut streams = Vec::new();
+				for _ in 0..2 {
+					streams.push(c_mux.open(&dst).await.unwrap());
+				}
+				for _ in 0..2 {
+					let (d, mut s) = incoming.recv().await.unwrap();
+					assert_eq!(dst.to_string(), d.to_string());
+					spawn_local(async move {
+						let (mut r, mut w) = split(&mut s);
+						tokio::io::copy(&mut r, &mut w).await.unwrap();
+						w.shutdown().await.unwrap();
+					});
+				}
+				for s in streams {
+					let (mut r, mut w) = split(s);
+					let (_, echoed) = tokio::join!(
+						async {
+							w.write_all(&data).await.unwrap();
+							w.shutdown().await.unwrap();
+						},
+						async {
+							let mut v = Vec::new();
+							r.read_to_end(&mut v).await.unwrap();
+							v
+						}
+					);
+					assert!(echoed == data);
+				}
+				// streams are removed once both sides are done
+				for _ in 0..0x10 {
+					tokio::task::yield_now().await;
+				}
+				assert_eq!(0, c_mux.len());
+			})
+			.await;
+	}
+
+	#[tokio::test]
+	async fn test_keepalive() {
+		LocalSet::new()
+			.run_until(async {
+				let interval = Duration::from_millis(50);
+				// answered by the peer
+				let (c, s) = tokio::io::duplex(0x1000);
+				let (c_mux, _) = Mux::new(c, true);
+				let (_s_mux, _) = Mux::new(s, false);
+				c_mux.keepalive(interval);
+				tokio::time::sleep(interval * 4).await;
+				assert!(!c_mux.is_closed());
+
+				// not, as if the connection is dead
+				let (c, _s) = tokio::io::duplex(0x1000);
+				let (c_mux, _) = Mux::new(c, true);
+				c_mux.keepalive(interval);
+				tokio::time::sleep(interval * 3).await;
+				assert!(c_mux.is_closed());
+			})
+			.await;
+	}
+}
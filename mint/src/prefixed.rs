@@ -0,0 +1,56 @@
+// a stream with what's already read from it put back in front,
+// like early data left in the handshake buffer
+
+use std::{
+	io,
+	pin::Pin,
+	task::{Context, Poll},
+};
+
+use chacha20poly1305::aead::bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub struct Prefixed<T> {
+	prefix: BytesMut,
+	inner: T,
+}
+
+impl<T> Prefixed<T> {
+	pub fn new(inner: T, prefix: BytesMut) -> Self {
+		Self { prefix, inner }
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Prefixed<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		if self.prefix.is_empty() {
+			return Pin::new(&mut self.inner).poll_read(cx, buf);
+		}
+		let n = self.prefix.len().min(buf.remaining());
+		buf.put_slice(&self.prefix[..n]);
+		self.prefix.advance(n);
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Prefixed<T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
@@ -1,89 +1,427 @@
+use std::rc::Rc;
+
 use chacha20poly1305::aead::{
-	AeadCore, AeadInOut, Generate as _, KeyInit, Nonce,
+	AeadCore, AeadInOut, Generate as _, KeyInit, KeySizeUser, Nonce, Tag,
 	bytes::{BufMut, BytesMut},
 };
 use log::*;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy, split};
+use socks5::Dst;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind, copy, split};
+
+use crate::{
+	fake::{self, Header},
+	key::{Psk, User},
+	replay::{Replay, now},
+};
 
 const EOH: &[u8] = b"\r\n\r\n";
 
-const VER: u8 = 0;
+// records are not compatible with earlier versions
+const VER: u8 = 4;
+
 const REP_OK: u8 = 0;
+const REP_FULL_REQUIRED: u8 = 1;
+const REP_CONNECT_FAILED: u8 = 2;
+
+// flags in request and response
+const FLAG_FULL: u8 = 1;
+const FLAG_EARLY: u8 = 2;
+// streams multiplexed over the session, only with FLAG_FULL
+const FLAG_MUX: u8 = 4;
+// datagrams framed over the session, only with FLAG_FULL
+const FLAG_UDP: u8 = 8;
+// a service registered by an agent, muxed with streams opened by the server, only with FLAG_FULL
+const FLAG_REVERSE: u8 = 0x10;
+
+// max early data in a request, so it stays within MAX_MSG
+pub const MAX_EARLY: usize = 0x1000;
+
+// max plain data in a record, so the length fits in u16
+const MAX_RECORD: usize = 0x4000;
+
+// sizes padded to, by the bucket policy
+const BUCKETS: &[usize] = &[0x100, 0x400, 0x1000, MAX_RECORD];
+
+// a full TCP segment, on a 1500 MTU with timestamps
+const SEGMENT: usize = 1448;
+
+// max size of a handshake message, fake header included
+const MAX_MSG: usize = 0x2000;
+
+// max chunk size line after a chunked fake header, hex digits and CRLF
+const CHUNK_LINE: usize = 10;
+
+const SALT_LEN: usize = 32;
+pub type Salt = [u8; SALT_LEN];
+
+// of records sent, each side picks its own
+#[derive(clap::ValueEnum, Clone, Copy, Default)]
+pub enum Padding {
+	#[default]
+	None,
+	// up to 0xff bytes
+	Random,
+	// to one of BUCKETS
+	Bucket,
+	// short records like HTTP headers, longer ones filling up TCP segments
+	Http,
+}
+
+impl Padding {
+	// for n bytes of data
+	fn len(self, n: usize) -> usize {
+		let padded = match self {
+			Padding::None => n,
+			Padding::Random => n + rand::random_range(0..0x100),
+			Padding::Bucket => BUCKETS.iter().copied().find(|&b| b >= n).unwrap_or(n),
+			Padding::Http if n < 0x200 => rand::random_range(0x180..0x500),
+			Padding::Http => n.next_multiple_of(SEGMENT),
+		};
+		padded.clamp(n, MAX_RECORD.max(n)) - n
+	}
+}
+
+// one direction of a session
+pub struct Half<C> {
+	cipher: C,
+	// for the length mask
+	mask: C,
+	seq: u64,
+	padding: Padding,
+}
+
+impl<C: AeadCore + AeadInOut> Half<C> {
+	// implicit, never sent, so records can't be replayed, reordered or dropped
+	fn next_nonce(&mut self) -> Nonce<C> {
+		let mut nonce = Nonce::<C>::default();
+		nonce[..8].copy_from_slice(&self.seq.to_le_bytes());
+		self.seq += 1;
+		nonce
+	}
+
+	// keystream of the mask cipher, over zeros
+	fn mask(&self, nonce: &Nonce<C>) -> u16 {
+		let mut m = [0u8; 2];
+		self.mask
+			.encrypt_inout_detached(nonce, b"", (&mut m[..]).into())
+			.unwrap();
+		u16::from_be_bytes(m)
+	}
+}
+
+// keys derived from the PSK and salts from both sides
+pub struct Session<C> {
+	tx: Half<C>,
+	rx: Half<C>,
+	pub full: bool,
+}
+
+impl<C: KeyInit> Session<C> {
+	fn new(psk: &Psk<C>, c_salt: &Salt, s_salt: &Salt, client: bool, full: bool) -> Self {
+		let mut salt = [0u8; SALT_LEN * 2];
+		salt[..SALT_LEN].copy_from_slice(c_salt);
+		salt[SALT_LEN..].copy_from_slice(s_salt);
+		let half = |info: &[u8], mask_info: &[u8]| Half {
+			cipher: psk.derive(&salt, info),
+			mask: psk.derive(&salt, mask_info),
+			seq: 0,
+			padding: Padding::None,
+		};
+		let c2s = half(b"mint c2s", b"mint c2s len");
+		let s2c = half(b"mint s2c", b"mint s2c len");
+		let (tx, rx) = if client { (c2s, s2c) } else { (s2c, c2s) };
+		Self { tx, rx, full }
+	}
+
+	// of records sent
+	pub fn padding(mut self, padding: Padding) -> Self {
+		self.tx.padding = padding;
+		self
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+	// only the first few records are encrypted
+	Plain,
+	Full,
+	// full, carrying multiplexed streams instead of a single one
+	Mux,
+	// full, carrying datagrams instead of a stream
+	Udp,
+	// full, carrying streams to a service of an agent
+	Reverse,
+}
 
 pub async fn client_handshake<
 	T: AsyncRead + AsyncWrite + Unpin,
 	C: KeyInit + AeadCore + AeadInOut,
 >(
 	io: &mut T,
-	cipher: &C,
+	psk: &Psk<C>,
 	buf: &mut BytesMut,
-	host: &str,
-	port: u16,
-	header: &[u8],
-) -> Option<()> {
+	dst: &Dst<'_>,
+	header: &Header,
+	mode: Mode,
+	early: &[u8],
+) -> Option<Connected<C>> {
+	let full = mode != Mode::Plain;
+	let mut flags = match mode {
+		Mode::Plain => 0,
+		Mode::Full => FLAG_FULL,
+		Mode::Mux => FLAG_FULL | FLAG_MUX,
+		Mode::Udp => FLAG_FULL | FLAG_UDP,
+		Mode::Reverse => FLAG_FULL | FLAG_REVERSE,
+	};
+	if !early.is_empty() {
+		flags |= FLAG_EARLY;
+	}
+	let salt: Salt = rand::random();
 	buf.clear();
-	write_msg(buf, cipher, header, &Req(host, port));
+	// a mismatch fails like a wrong PSK
+	write_msg(
+		buf,
+		&psk.cipher,
+		header,
+		None,
+		psk.name.as_bytes(),
+		&Req {
+			host: &dst.addr.to_string(),
+			port: dst.port,
+			flags,
+			salt,
+			time: now(),
+			early,
+		},
+	);
 	io.write_all(buf)
 		.await
 		.inspect_err(|e| debug!("handshake error writing: {e}"))
 		.ok()?;
 
-	buf.clear();
-	io.read_buf(buf)
+	let Some(len) = read_frame::<C, _>(io, buf)
 		.await
-		.inspect_err(|e| debug!("handshake error reading: {e}"))
-		.ok()?;
-	let resp: Resp = read_msg(buf, cipher)?;
-	if resp.0 != REP_OK {
-		debug!("server replies 0x{:02x}, unexpected", resp.0);
+		.inspect_err(|e| match e.kind() {
+			ErrorKind::UnexpectedEof => {
+				error!("server closed the connection, the PSK or cipher may not match")
+			}
+			_ => debug!("handshake error reading: {e}"),
+		})
+		.ok()?
+	else {
+		error!("invalid response, the PSK or cipher may not match");
+		return None;
+	};
+	// anything after it is early data from the server
+	let mut msg = buf.split_to(len);
+	// bound to the request
+	let resp: Resp = read_msg(&mut msg, &psk.cipher, &salt)?;
+	match resp.rep {
+		REP_OK => {}
+		REP_FULL_REQUIRED => {
+			error!("server requires full mode");
+			return None;
+		}
+		REP_CONNECT_FAILED => {
+			info!("server failed connecting to {dst}");
+			return Some(Connected::Failed);
+		}
+		r => {
+			debug!("server replies 0x{r:02x}, unexpected");
+			return None;
+		}
+	}
+	if resp.flags != flags {
+		error!(
+			"server replies flags 0x{:02x}, expecting 0x{flags:02x}",
+			resp.flags
+		);
 		return None;
 	}
 
-	Some(())
+	Some(Connected::Ok(Session::new(
+		psk, &salt, &resp.salt, true, full,
+	)))
+}
+
+// None is the server failing, or the way to it
+pub enum Connected<C> {
+	Ok(Session<C>),
+	// the server failed connecting to the destination, with early data
+	Failed,
+}
+
+// a valid request, to be replied with reply(),
+// after connecting if there's early data, before that otherwise
+pub struct Request<C: KeySizeUser> {
+	pub host: String,
+	pub port: u16,
+	// to be written to the destination before replying
+	pub early: Option<Vec<u8>>,
+	// whose key it is
+	pub user: Rc<str>,
+	psk: Psk<C>,
+	salt: Salt,
+	// in the fake header, for the one in the response
+	ws_key: Option<String>,
+	flags: u8,
+}
+
+// after handshakes, buf is left with early data, which should be read before the stream
+pub enum Accept<C: KeySizeUser> {
+	Ok(Request<C>),
+	// not a valid handshake, buf is left with everything read so far
+	Invalid,
 }
 
 pub async fn server_handshake<
 	T: AsyncRead + AsyncWrite + Unpin,
-	C: KeyInit + AeadCore + AeadInOut,
+	C: KeyInit + AeadCore + AeadInOut + Clone,
 >(
 	io: &mut T,
-	cipher: &C,
+	users: &[User<C>],
 	buf: &mut BytesMut,
-	header: &[u8],
-) -> Option<(String, u16)> {
-	buf.clear();
-	io.read_buf(buf)
+	header: &Header,
+	require_full: bool,
+	replay: &Replay,
+) -> Option<Accept<C>> {
+	let Some(len) = read_frame::<C, _>(io, buf)
 		.await
 		.inspect_err(|e| debug!("handshake error reading: {e}"))
-		.ok()?;
-	let req: Req = read_msg(buf, cipher)?;
+		.ok()?
+	else {
+		return Some(Accept::Invalid);
+	};
+	// decrypted in place, keep the original for the fallback
+	let raw = buf.clone();
+	let msg = buf.split_to(len);
+	// trial decryption, with every key not expired
+	let now = now();
+	let Some((user, msg, offset)) = users
+		.iter()
+		.filter(|u| !u.psk.is_expired(now))
+		.find_map(|u| {
+			let mut m = msg.clone();
+			let offset = open_msg(&mut m, &u.psk.cipher, u.psk.name.as_bytes())?;
+			Some((u, m, offset))
+		})
+	else {
+		*buf = raw;
+		return Some(Accept::Invalid);
+	};
+	let Some(req) = Req::read(&msg[offset..]) else {
+		*buf = raw;
+		return Some(Accept::Invalid);
+	};
+	// treated the same as invalid messages
+	if !replay.check(req.time, &req.salt) {
+		*buf = raw;
+		return Some(Accept::Invalid);
+	}
 
-	let host = req.0.to_owned();
-	let port = req.1;
+	let req = Request {
+		host: req.host.to_owned(),
+		port: req.port,
+		early: (req.flags & FLAG_EARLY != 0).then(|| req.early.to_vec()),
+		user: user.name.clone(),
+		psk: user.psk.clone(),
+		salt: req.salt,
+		ws_key: eoh(&raw).and_then(|eoh| fake::ws_key(&raw[..eoh])),
+		// unknown flags are not echoed back
+		flags: match req.flags & FLAG_FULL {
+			0 => req.flags & FLAG_EARLY,
+			_ => req.flags & (FLAG_FULL | FLAG_EARLY | FLAG_MUX | FLAG_UDP | FLAG_REVERSE),
+		},
+	};
 
-	buf.clear();
-	write_msg(buf, cipher, header, &Resp(REP_OK));
-	io.write_all(buf)
+	if require_full && req.flags & FLAG_FULL == 0 {
+		write_resp(io, header, &req, REP_FULL_REQUIRED).await?;
+		debug!("client of {} refused, not in full mode", req.user);
+		return None;
+	}
+
+	Some(Accept::Ok(req))
+}
+
+impl<C: KeySizeUser> Request<C> {
+	// the destination is not used, streams carry their own
+	pub fn mux(&self) -> bool {
+		self.flags & FLAG_MUX != 0
+	}
+
+	// the destination is not used, datagrams carry their own
+	pub fn udp(&self) -> bool {
+		self.flags & FLAG_UDP != 0
+	}
+
+	// the host is the name of the service, the port is not used
+	pub fn reverse(&self) -> bool {
+		self.flags & FLAG_REVERSE != 0
+	}
+}
+
+// returns the session if ok
+pub async fn reply<T: AsyncWrite + Unpin, C: KeyInit + AeadCore + AeadInOut>(
+	io: &mut T,
+	header: &Header,
+	req: &Request<C>,
+	ok: bool,
+) -> Option<Session<C>> {
+	if !ok {
+		write_resp(io, header, req, REP_CONNECT_FAILED).await?;
+		return None;
+	}
+	let salt = write_resp(io, header, req, REP_OK).await?;
+	let full = req.flags & FLAG_FULL != 0;
+	Some(Session::new(&req.psk, &req.salt, &salt, false, full))
+}
+
+// returns the server salt
+async fn write_resp<T: AsyncWrite + Unpin, C: KeyInit + AeadCore + AeadInOut>(
+	io: &mut T,
+	header: &Header,
+	req: &Request<C>,
+	rep: u8,
+) -> Option<Salt> {
+	let salt: Salt = rand::random();
+	let mut buf = BytesMut::with_capacity(0x600);
+	write_msg(
+		&mut buf,
+		&req.psk.cipher,
+		header,
+		req.ws_key.as_deref(),
+		&req.salt,
+		&Resp {
+			rep,
+			flags: req.flags,
+			salt,
+		},
+	);
+	io.write_all(&buf)
 		.await
 		.inspect_err(|e| debug!("handshake error writing: {e}"))
 		.ok()?;
-
-	// debug!("buf capacity: {}", buf.capacity());
-	Some((host, port))
+	Some(salt)
 }
 
 // can't be implemented on BufMut since we want encrypt in place
 fn write_msg<'a, C: AeadCore + AeadInOut>(
 	buf: &mut BytesMut,
 	cipher: &C,
-	header: &[u8],
+	header: &Header,
+	ws_key: Option<&str>,
+	aad: &[u8],
 	payload: &impl Payload<'a>,
 ) {
-	buf.put_slice(header);
+	let start = buf.len();
 
 	let nonce = Nonce::<C>::generate();
 	buf.put_slice(&nonce);
 
+	// we don't have length yet
+	let len_offset = buf.len();
+	buf.put_u16(0);
+
 	let payload_offset = buf.len();
 
 	payload.write(&mut *buf);
@@ -93,41 +431,134 @@ fn write_msg<'a, C: AeadCore + AeadInOut>(
 
 	let mut payload = buf.split_off(payload_offset);
 
-	cipher.encrypt_in_place(&nonce, b"", &mut payload).unwrap();
+	cipher.encrypt_in_place(&nonce, aad, &mut payload).unwrap();
+
+	let len = obfuscate(payload.len() as u16, &nonce).to_be_bytes();
+	buf[len_offset..].copy_from_slice(&len);
 
 	buf.unsplit(payload);
+
+	// the header goes in front, now that the length of the message is known
+	let msg = buf.split_off(start);
+	header.render(buf, msg.len(), ws_key);
+	buf.unsplit(msg);
 }
 
-fn read_msg<'a, C: AeadCore + AeadInOut, T: Payload<'a>>(
-	buf: &'a mut BytesMut,
-	cipher: &C,
-) -> Option<T> {
-	let Some(eoh) = buf.as_ref().windows(EOH.len()).position(|w| w == EOH) else {
-		debug!("EoH not found, unexpected");
-		return None;
+enum Frame {
+	Partial,
+	Invalid,
+	// length of the message
+	Complete(usize),
+}
+
+fn eoh(buf: &[u8]) -> Option<usize> {
+	buf.windows(EOH.len()).position(|w| w == EOH)
+}
+
+// where the message starts, after the header, and the chunk size line if it's chunked
+// as Frame::Complete
+fn msg_offset(buf: &[u8]) -> Frame {
+	let Some(eoh) = eoh(buf) else {
+		if buf.len() >= MAX_MSG {
+			debug!("EoH not found, unexpected");
+			return Frame::Invalid;
+		}
+		return Frame::Partial;
 	};
 
-	let nonce_offset = eoh + EOH.len();
+	let offset = eoh + EOH.len();
+	if !fake::chunked(&buf[..eoh]) {
+		return Frame::Complete(offset);
+	}
+	let line = &buf[offset..buf.len().min(offset + CHUNK_LINE)];
+	let Some(end) = line.windows(2).position(|w| w == b"\r\n") else {
+		if line.len() == CHUNK_LINE {
+			debug!("chunk size not found, unexpected");
+			return Frame::Invalid;
+		}
+		return Frame::Partial;
+	};
+	if end == 0 || !line[..end].iter().all(u8::is_ascii_hexdigit) {
+		debug!("invalid chunk size");
+		return Frame::Invalid;
+	}
+	Frame::Complete(offset + end + 2)
+}
 
-	let payload_offset = nonce_offset + nonce_size::<C>();
+// how much of what's read so far is the message
+fn frame<C: AeadCore>(buf: &[u8]) -> Frame {
+	let nonce_offset = match msg_offset(buf) {
+		Frame::Complete(offset) => offset,
+		f => return f,
+	};
+
+	let len_offset = nonce_offset + nonce_size::<C>();
+	let payload_offset = len_offset + 2;
 	if buf.len() < payload_offset {
-		if buf.len() == nonce_offset {
-			debug!("invalid msg, likely just HTTP");
-		} else {
-			debug!("invalid msg, no nonce");
+		return Frame::Partial;
+	}
+	let len = u16::from_be_bytes([buf[len_offset], buf[len_offset + 1]]);
+	let len = obfuscate(len, &buf[nonce_offset..len_offset]) as usize;
+	if len < tag_size::<C>() || payload_offset + len > MAX_MSG {
+		debug!("invalid msg length {len}");
+		return Frame::Invalid;
+	}
+	if buf.len() < payload_offset + len {
+		return Frame::Partial;
+	}
+	Frame::Complete(payload_offset + len)
+}
+
+// reads until buf has a whole message, anything after it is left in buf
+// Ok(None) if it's not a valid message
+async fn read_frame<C: AeadCore, T: AsyncRead + Unpin>(
+	io: &mut T,
+	buf: &mut BytesMut,
+) -> io::Result<Option<usize>> {
+	buf.clear();
+	// reads may end anywhere, even right after the header, like with a small MSS,
+	// plain HTTP requests are left to the handshake timeout
+	loop {
+		if io.read_buf(buf).await? == 0 {
+			if buf.is_empty() {
+				return Err(ErrorKind::UnexpectedEof.into());
+			}
+			debug!("EOF before a whole message");
+			return Ok(None);
+		}
+		match frame::<C>(buf) {
+			Frame::Partial => {}
+			Frame::Invalid => return Ok(None),
+			Frame::Complete(len) => return Ok(Some(len)),
 		}
-		return None;
 	}
-	// length was checked beforehand, it's safe to unwrap here
+}
+
+fn read_msg<'a, C: AeadCore + AeadInOut, T: Payload<'a>>(
+	buf: &'a mut BytesMut,
+	cipher: &C,
+	aad: &[u8],
+) -> Option<T> {
+	let offset = open_msg(buf, cipher, aad)?;
+	Payload::read(&buf[offset..])
+}
+
+// decrypts in place, returns where the payload is
+fn open_msg<C: AeadCore + AeadInOut>(buf: &mut BytesMut, cipher: &C, aad: &[u8]) -> Option<usize> {
+	// it's checked by frame() beforehand
+	let Frame::Complete(nonce_offset) = msg_offset(buf) else {
+		unreachable!();
+	};
+
+	let payload_offset = nonce_offset + nonce_size::<C>() + 2;
 	let nonce = Nonce::<C>::try_from(&buf[nonce_offset..nonce_offset + nonce_size::<C>()]).unwrap();
 	let mut payload = buf.split_off(payload_offset);
-	if let Err(e) = cipher.decrypt_in_place(&nonce, b"", &mut payload) {
+	if let Err(e) = cipher.decrypt_in_place(&nonce, aad, &mut payload) {
 		debug!("failed to decrypt message, likely invalid: {e}");
 		return None;
 	}
 	buf.unsplit(payload);
-
-	Payload::read(&buf[payload_offset..])
+	Some(payload_offset)
 }
 
 trait Payload<'a>: Sized {
@@ -136,20 +567,41 @@ trait Payload<'a>: Sized {
 }
 
 #[derive(Debug, PartialEq, Eq)]
-struct Req<'a>(&'a str, u16);
+struct Req<'a> {
+	host: &'a str,
+	port: u16,
+	flags: u8,
+	salt: Salt,
+	// unix time in seconds
+	time: u64,
+	// with FLAG_EARLY
+	early: &'a [u8],
+}
 
 #[derive(Debug, PartialEq, Eq)]
-struct Resp(u8);
+struct Resp {
+	rep: u8,
+	flags: u8,
+	salt: Salt,
+}
 
 impl<'a> Payload<'a> for Req<'a> {
 	fn write(&self, mut buf: impl BufMut) {
 		buf.put_u8(VER);
-		buf.put_u8(self.0.len() as u8);
-		buf.put_slice(self.0.as_bytes());
-		buf.put_u16(self.1);
+		buf.put_u8(self.flags);
+		buf.put_slice(&self.salt);
+		buf.put_u64(self.time);
+		buf.put_u8(self.host.len() as u8);
+		buf.put_slice(self.host.as_bytes());
+		buf.put_u16(self.port);
+		if self.flags & FLAG_EARLY != 0 {
+			buf.put_u16(self.early.len() as u16);
+			buf.put_slice(self.early);
+		}
 	}
 	fn read(buf: &'a [u8]) -> Option<Self> {
-		if buf.len() < 2 {
+		const HOST_OFFSET: usize = 2 + SALT_LEN + 8 + 1;
+		if buf.len() < HOST_OFFSET {
 			error!("invalid request length: {}", buf.len());
 			return None;
 		}
@@ -158,108 +610,142 @@ impl<'a> Payload<'a> for Req<'a> {
 			error!("invalid ver: 0x{ver:02x}");
 			return None;
 		}
-		let len = buf[1];
-		if buf.len() < 2 + len as usize + 2 {
+		let flags = buf[1];
+		let salt = buf[2..2 + SALT_LEN].try_into().unwrap();
+		let time = u64::from_be_bytes(buf[2 + SALT_LEN..2 + SALT_LEN + 8].try_into().unwrap());
+		let len = buf[HOST_OFFSET - 1] as usize;
+		if buf.len() < HOST_OFFSET + len + 2 {
 			error!(
 				"invalid request length: {} < {}",
 				buf.len(),
-				2 + len as usize + 2
+				HOST_OFFSET + len + 2
 			);
 			return None;
 		}
-		let Ok(host) = str::from_utf8(&buf[2..2 + len as usize]) else {
+		let Ok(host) = str::from_utf8(&buf[HOST_OFFSET..HOST_OFFSET + len]) else {
 			error!("invalid utf8 in host");
 			return None;
 		};
 		let port = u16::from_be_bytes(
-			buf[2 + len as usize..2 + len as usize + 2]
+			buf[HOST_OFFSET + len..HOST_OFFSET + len + 2]
 				.try_into()
 				.unwrap(),
 		);
-		Some(Req(host, port))
+		let mut early: &[u8] = &[];
+		if flags & FLAG_EARLY != 0 {
+			let o = HOST_OFFSET + len + 2;
+			if buf.len() < o + 2 {
+				error!("invalid request length: no early data");
+				return None;
+			}
+			let l = u16::from_be_bytes(buf[o..o + 2].try_into().unwrap()) as usize;
+			if buf.len() < o + 2 + l {
+				error!("invalid early data length: {l}");
+				return None;
+			}
+			early = &buf[o + 2..o + 2 + l];
+		}
+		Some(Req {
+			host,
+			port,
+			flags,
+			salt,
+			time,
+			early,
+		})
 	}
 }
 
 impl<'a> Payload<'a> for Resp {
 	fn write(&self, mut buf: impl BufMut) {
-		buf.put_u8(self.0);
+		buf.put_u8(self.rep);
+		buf.put_u8(self.flags);
+		buf.put_slice(&self.salt);
 	}
 	fn read(buf: &'a [u8]) -> Option<Self> {
-		if buf.is_empty() {
+		if buf.len() < 2 + SALT_LEN {
 			error!("invalid response length: {}", buf.len());
 			return None;
 		}
-		Some(Resp(buf[0]))
+		Some(Resp {
+			rep: buf[0],
+			flags: buf[1],
+			salt: buf[2..2 + SALT_LEN].try_into().unwrap(),
+		})
 	}
 }
 
 // read once from the plain side, encrypt it, write it to the encrypted side
+// at EOF of the plain side, a record with no data is written, so truncation can be told apart
 async fn enc1<C: AeadCore + AeadInOut, E: AsyncWrite + Unpin, P: AsyncRead + Unpin>(
 	buf: &mut BytesMut,
-	cipher: &C,
+	half: &mut Half<C>,
 	encrypted: &mut E,
 	plain: &mut P,
 ) -> Option<()> {
 	buf.clear();
 
-	// we don't generate nonce at this point
-	buf.put_bytes(0, nonce_size::<C>());
-
-	// we don't have length yet
+	// we don't have lengths yet, of the record, and of the data in it
 	buf.put_u16(0);
-	let payload_offset = buf.len();
+	buf.put_u16(0);
+	let payload_offset = 2;
+	let data_offset = buf.len();
 
-	if let Err(e) = plain.read_buf(buf).await {
+	if let Err(e) = plain.read_buf(&mut (&mut *buf).limit(MAX_RECORD)).await {
 		debug!("failed to read plain data: {e}");
 		return None;
 	}
+	let n = buf.len() - data_offset;
+	let eof = n == 0;
+	buf[payload_offset..data_offset].copy_from_slice(&(n as u16).to_be_bytes());
+	buf.put_bytes(0, half.padding.len(n));
 	let mut payload = buf.split_off(payload_offset);
-	if payload.is_empty() {
-		debug!("got 0 reading plain data, likely remote closed");
-		buf.unsplit(payload);
-		return None;
-	}
 
-	let nonce = Nonce::<C>::generate();
-	if let Err(e) = cipher.encrypt_in_place(&nonce, b"", &mut payload) {
+	let nonce = half.next_nonce();
+	if let Err(e) = half.cipher.encrypt_in_place(&nonce, b"", &mut payload) {
 		error!("failed to encrypt: {e}");
 		return None;
 	}
-	// write nonce
-	buf[..nonce_size::<C>()].copy_from_slice(&nonce);
 	// write length
-	let len = obfuscate(payload.len() as u16, &nonce).to_be_bytes();
-	buf[nonce_size::<C>()..].copy_from_slice(&len);
+	let len = (payload.len() as u16 ^ half.mask(&nonce)).to_be_bytes();
+	buf[..].copy_from_slice(&len);
 	buf.unsplit(payload);
 
 	encrypted
 		.write_all(buf)
 		.await
 		.inspect_err(|e| debug!("failed to write encrypted data: {e}"))
-		.ok()
+		.ok()?;
+
+	if eof {
+		debug!("got 0 reading plain data, likely remote closed");
+		return None;
+	}
+	Some(())
 }
 
 // read one _packet_ from the encrypted side, decrypt it, write it to the plain side
 async fn dec1<C: AeadCore + AeadInOut, P: AsyncWrite + Unpin, E: AsyncRead + Unpin>(
 	buf: &mut BytesMut,
-	cipher: &C,
+	half: &mut Half<C>,
 	plain: &mut P,
 	encrypted: &mut E,
 ) -> Option<()> {
-	let mut nonce = Nonce::<C>::default();
-	if let Err(e) = encrypted.read_exact(&mut nonce).await {
-		debug!("failed to read nonce: {e}");
-		return None;
-	}
-
-	let len = encrypted
-		.read_u16()
-		.await
-		.inspect_err(|e| debug!("failed to read len: {e}"))
-		.ok()?;
-	let len = obfuscate(len, &nonce);
-	if len == 0 {
-		debug!("length = 0, unexpected");
+	let len = match encrypted.read_u16().await {
+		Ok(len) => len,
+		Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+			error!("encrypted side closed without the final record, truncated");
+			return None;
+		}
+		Err(e) => {
+			debug!("failed to read len: {e}");
+			return None;
+		}
+	};
+	let nonce = half.next_nonce();
+	let len = len ^ half.mask(&nonce);
+	if (len as usize) < tag_size::<C>() {
+		error!("length = {len}, unexpected");
 		return None;
 	}
 
@@ -270,13 +756,27 @@ async fn dec1<C: AeadCore + AeadInOut, P: AsyncWrite + Unpin, E: AsyncRead + Unp
 		return None;
 	}
 
-	if let Err(e) = cipher.decrypt_in_place(&nonce, b"", buf) {
+	if let Err(e) = half.cipher.decrypt_in_place(&nonce, b"", buf) {
 		error!("failed to decrypt payload: {e}");
 		return None;
 	}
 
+	// followed by padding
+	let n = match buf[..] {
+		[a, b, ..] => u16::from_be_bytes([a, b]) as usize,
+		_ => usize::MAX,
+	};
+	if n > buf.len().saturating_sub(2) {
+		error!("data length = {n}, unexpected");
+		return None;
+	}
+	if n == 0 {
+		debug!("got the final record, remote closed");
+		return None;
+	}
+
 	plain
-		.write_all(buf)
+		.write_all(&buf[2..2 + n])
 		.await
 		.inspect_err(|e| {
 			error!("failed to write decrypted payload: {e}");
@@ -289,36 +789,44 @@ pub async fn duplex<
 	P: AsyncRead + AsyncWrite + Unpin,
 	E: AsyncRead + AsyncWrite + Unpin,
 >(
-	cipher: &C,
+	session: Session<C>,
 	plain: &mut P,
 	encrypted: &mut E,
 ) {
 	let (mut p_r, mut p_w) = split(plain);
 	let (mut e_r, mut e_w) = split(encrypted);
+	let full = session.full;
 	tokio::join!(
-		simplex(cipher, enc1, &mut e_w, &mut p_r),
-		simplex(cipher, dec1, &mut p_w, &mut e_r),
+		simplex(session.tx, enc1, &mut e_w, &mut p_r, full),
+		simplex(session.rx, dec1, &mut p_w, &mut e_r, full),
 	);
 }
 
 pub async fn simplex<
 	C: AeadCore + AeadInOut,
-	F: AsyncFn(&mut BytesMut, &C, &mut W, &mut R) -> Option<()>,
+	F: AsyncFn(&mut BytesMut, &mut Half<C>, &mut W, &mut R) -> Option<()>,
 	W: AsyncWrite + Unpin,
 	R: AsyncRead + Unpin,
 >(
-	cipher: &C,
+	mut half: Half<C>,
 	codec: F,
 	w: &mut W,
 	r: &mut R,
+	full: bool,
 ) -> Option<()> {
 	// enclosed so I can use ? and still guarantee shutdown
 	// is there a better pattern?
 	async {
 		let mut buf = BytesMut::with_capacity(0x1000);
-		codec(&mut buf, cipher, w, r).await?;
-		codec(&mut buf, cipher, w, r).await?;
-		codec(&mut buf, cipher, w, r).await?;
+		// every record is encrypted, until either side closes
+		if full {
+			loop {
+				codec(&mut buf, &mut half, w, r).await?;
+			}
+		}
+		codec(&mut buf, &mut half, w, r).await?;
+		codec(&mut buf, &mut half, w, r).await?;
+		codec(&mut buf, &mut half, w, r).await?;
 		drop(buf);
 		copy(r, w)
 			.await
@@ -337,6 +845,10 @@ const fn nonce_size<C: AeadCore>() -> usize {
 	std::mem::size_of::<Nonce<C>>()
 }
 
+const fn tag_size<C: AeadCore>() -> usize {
+	std::mem::size_of::<Tag<C>>()
+}
+
 // make len look random
 fn obfuscate(a: u16, b: &[u8]) -> u16 {
 	a ^ u16::from_be_bytes([b[4 % b.len()], b[2 % b.len()]])
@@ -344,32 +856,121 @@ fn obfuscate(a: u16, b: &[u8]) -> u16 {
 
 #[cfg(test)]
 mod test {
+	use std::{
+		pin::Pin,
+		task::{Context, Poll},
+		time::Duration,
+	};
+
 	use chacha20poly1305::{
 		ChaCha8Poly1305 as Cipher,
-		aead::{Generate as _, Key, KeyInit, Nonce, bytes::BytesMut},
+		aead::{Generate as _, Key, Nonce, bytes::BytesMut},
 	};
 
+	use socks5::Addr;
+
 	use super::*;
+	use crate::fake::Preset;
 
 	fn init() {
 		let _ = env_logger::builder().is_test(true).try_init();
 	}
 
+	fn psk() -> Psk<Cipher> {
+		Psk::new(Key::<Cipher>::generate())
+	}
+
+	fn users<C: crate::key::Cipher>(psk: &Psk<C>) -> Vec<User<C>> {
+		vec![User {
+			name: "test".into(),
+			psk: psk.clone(),
+		}]
+	}
+
+	// client and server side of the same session
+	fn sessions(psk: &Psk<Cipher>, full: bool) -> (Session<Cipher>, Session<Cipher>) {
+		let (c_salt, s_salt) = (rand::random(), rand::random());
+		(
+			Session::new(psk, &c_salt, &s_salt, true, full),
+			Session::new(psk, &c_salt, &s_salt, false, full),
+		)
+	}
+
 	#[test]
 	fn test_payload() {
 		init();
 
-		let key = Key::<Cipher>::generate();
-		println!("key len: {}", key.len());
-		let cipher = Cipher::new(&key);
+		let psk = psk();
 		let nonce = Nonce::<Cipher>::generate();
 		println!("nonce len: {}", nonce.len());
 		assert_eq!(nonce.len(), nonce_size::<Cipher>());
 
 		let mut buf = BytesMut::with_capacity(1024);
-		let req = Req("example.com", 443);
-		write_msg(&mut buf, &cipher, EOH, &req);
-		let req_r: Req = read_msg(&mut buf, &cipher).unwrap();
+		let req = Req {
+			host: "example.com",
+			port: 443,
+			flags: FLAG_FULL | FLAG_EARLY,
+			salt: rand::random(),
+			time: now(),
+			early: b"hello",
+		};
+		write_msg(&mut buf, &psk.cipher, &Header::default(), None, b"", &req);
+		assert!(matches!(frame::<Cipher>(&buf), Frame::Complete(n) if n == buf.len()));
+		assert!(matches!(frame::<Cipher>(&buf[..40]), Frame::Partial));
+		let req_r: Req = read_msg(&mut buf, &psk.cipher, b"").unwrap();
+		assert_eq!(req, req_r);
+
+		// responses are bound to the request
+		let resp = Resp {
+			rep: REP_OK,
+			flags: 0,
+			salt: rand::random(),
+		};
+		buf.clear();
+		write_msg(
+			&mut buf,
+			&psk.cipher,
+			&Header::default(),
+			None,
+			&req.salt,
+			&resp,
+		);
+		assert_eq!(
+			None,
+			read_msg::<_, Resp>(&mut buf.clone(), &psk.cipher, b"")
+		);
+		assert_eq!(Some(resp), read_msg(&mut buf, &psk.cipher, &req.salt));
+	}
+
+	#[test]
+	fn test_chunked() {
+		init();
+
+		let psk = psk();
+		let header = Header::load(
+			"",
+			Some(Preset::ChunkedPost),
+			true,
+			vec!["example.com".into()],
+		);
+		let req = Req {
+			host: "example.com",
+			port: 443,
+			flags: 0,
+			salt: rand::random(),
+			time: now(),
+			early: b"",
+		};
+		let mut buf = BytesMut::with_capacity(1024);
+		write_msg(&mut buf, &psk.cipher, &header, None, b"", &req);
+		let eoh = eoh(&buf).unwrap() + EOH.len();
+		assert!(matches!(frame::<Cipher>(&buf), Frame::Complete(n) if n == buf.len()));
+		// in the chunk size line
+		assert!(matches!(frame::<Cipher>(&buf[..eoh + 2]), Frame::Partial));
+		let mut invalid = buf.clone();
+		invalid[eoh] = b'x';
+		assert!(matches!(frame::<Cipher>(&invalid), Frame::Invalid));
+		let req_r: Req = read_msg(&mut buf, &psk.cipher, b"").unwrap();
 		assert_eq!(req, req_r);
 	}
 
@@ -379,48 +980,490 @@ mod test {
 
 		let (mut c, mut s) = tokio::io::duplex(0x500);
 
-		let key = Key::<Cipher>::generate();
-		let cipher = Cipher::new(&key);
+		let psk = psk();
+		let replay = Replay::new(60);
+		// the second one is tried too
+		let users = vec![
+			User {
+				name: "other".into(),
+				psk: self::psk(),
+			},
+			User {
+				name: "test".into(),
+				psk: psk.clone(),
+			},
+		];
 
-		tokio::join!(
+		let dst = Dst {
+			addr: Addr::Domain("example.com"),
+			port: 443,
+		};
+
+		let (c_sess, (req, s_sess)) = tokio::join!(
 			async {
 				let mut buf = BytesMut::with_capacity(0x500);
-				assert_eq!(
-					Some(()),
-					client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, EOH).await
+				let Some(Connected::Ok(sess)) = client_handshake(
+					&mut c,
+					&psk,
+					&mut buf,
+					&dst,
+					&Header::default(),
+					Mode::Full,
+					b"hello",
+				)
+				.await
+				else {
+					panic!("handshake failed");
+				};
+				assert!(
+					client_handshake(
+						&mut c,
+						&psk,
+						&mut buf,
+						&dst,
+						&Header::default(),
+						Mode::Plain,
+						b""
+					)
+					.await
+					.is_none()
 				);
+				sess
 			},
 			async {
 				let mut buf = BytesMut::with_capacity(0x500);
-				assert_eq!(
-					Some(("example.com".to_owned(), 443)),
-					server_handshake(&mut s, &cipher, &mut buf, EOH).await
+				let Some(Accept::Ok(req)) =
+					server_handshake(&mut s, &users, &mut buf, &Header::default(), true, &replay)
+						.await
+				else {
+					panic!("handshake failed");
+				};
+				let sess = reply(&mut s, &Header::default(), &req, true).await.unwrap();
+				assert!(
+					server_handshake(&mut s, &users, &mut buf, &Header::default(), true, &replay)
+						.await
+						.is_none()
 				);
+				(req, sess)
 			}
 		);
+		assert_eq!(("example.com", 443), (&req.host as &str, req.port));
+		assert_eq!(Some(&b"hello"[..]), req.early.as_deref());
+		assert_eq!("test", &*req.user);
+		assert!(c_sess.full && s_sess.full);
+
+		// both sides derived the same keys
+		let (mut c_tx, mut s_rx) = (c_sess.tx, s_sess.rx);
+		let nonce = c_tx.next_nonce();
+		assert_eq!(nonce, s_rx.next_nonce());
+		assert_eq!(c_tx.mask(&nonce), s_rx.mask(&nonce));
 	}
 
 	#[tokio::test]
-	async fn test_enc() {
+	async fn test_connect_failed() {
+		init();
+
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+
+		let psk = psk();
+		let replay = Replay::new(60);
+		let users = users(&psk);
+		let dst = Dst {
+			addr: Addr::Domain("example.com"),
+			port: 443,
+		};
+
+		let (c_sess, s_sess) = tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				client_handshake(
+					&mut c,
+					&psk,
+					&mut buf,
+					&dst,
+					&Header::default(),
+					Mode::Plain,
+					b"hello",
+				)
+				.await
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let Some(Accept::Ok(req)) =
+					server_handshake(&mut s, &users, &mut buf, &Header::default(), false, &replay)
+						.await
+				else {
+					panic!("handshake failed");
+				};
+				reply(&mut s, &Header::default(), &req, false).await
+			}
+		);
+		assert!(matches!(c_sess, Some(Connected::Failed)) && s_sess.is_none());
+	}
+
+	// a plain handshake with psk, against a server with the given users
+	async fn handshake_with<C: KeyInit + AeadCore + AeadInOut + Clone>(
+		psk: &Psk<Cipher>,
+		users: &[User<C>],
+	) -> Option<Accept<C>> {
+		let replay = Replay::new(60);
+		let dst = Dst {
+			addr: Addr::Domain("example.com"),
+			port: 443,
+		};
+
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+		let mut buf = BytesMut::with_capacity(0x500);
+		let (_, r) = tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				client_handshake(
+					&mut c,
+					psk,
+					&mut buf,
+					&dst,
+					&Header::default(),
+					Mode::Plain,
+					b"",
+				)
+				.await
+			},
+			async {
+				let r =
+					server_handshake(&mut s, users, &mut buf, &Header::default(), false, &replay)
+						.await;
+				// so the client sees it closed
+				drop(s);
+				r
+			}
+		);
+		r
+	}
+
+	#[tokio::test]
+	async fn test_cipher_mismatch() {
 		init();
 
 		let key = Key::<Cipher>::generate();
-		let cipher = Cipher::new(&key);
+		let psk = Psk::<Cipher>::new(key);
+		let aes = Psk::<aes_gcm::Aes256Gcm>::new(key.as_slice().try_into().unwrap());
+		let r = handshake_with(&psk, &users(&aes)).await;
+		assert!(matches!(r, Some(Accept::Invalid)));
+	}
+
+	#[tokio::test]
+	async fn test_expired() {
+		init();
+
+		let psk = psk();
+		let mut expired = psk.clone();
+		expired.not_after = Some(now());
+		let r = handshake_with(&psk, &users(&expired)).await;
+		assert!(matches!(r, Some(Accept::Invalid)));
+	}
+
+	#[tokio::test]
+	async fn test_invalid() {
+		init();
+
+		let psk = psk();
+		let replay = Replay::new(60);
+		let users = users(&psk);
+
+		let req = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+		c.write_all(req).await.unwrap();
+		let mut buf = BytesMut::with_capacity(0x500);
+		// more may follow the header, it's up to the handshake timeout while it's open
+		let header = Header::default();
+		{
+			let handshake = server_handshake(&mut s, &users, &mut buf, &header, false, &replay);
+			tokio::pin!(handshake);
+			let r = tokio::time::timeout(Duration::from_millis(50), &mut handshake).await;
+			assert!(r.is_err());
+			c.shutdown().await.unwrap();
+			assert!(matches!(handshake.await, Some(Accept::Invalid)));
+		}
+		// for the fallback
+		assert_eq!(&req[..], &buf[..]);
+	}
+
+	#[tokio::test]
+	async fn test_framed() {
+		init();
+
+		let psk = psk();
+		let replay = Replay::new(60);
+		let users = users(&psk);
+
+		let mut msg = BytesMut::new();
+		let req = Req {
+			host: "example.com",
+			port: 443,
+			flags: 0,
+			salt: rand::random(),
+			time: now(),
+			early: &[],
+		};
+		write_msg(
+			&mut msg,
+			&psk.cipher,
+			&Header::parse("GET / HTTP/1.1", vec![]),
+			None,
+			psk.name.as_bytes(),
+			&req,
+		);
+		// the last segment has the end of the message and early data together,
+		// or with random padding, a segment may end right where the message does,
+		// and the handshake would be done before early data arrives
+		let last = msg.len() - 0x10;
+		msg.put_slice(b"early data");
+
+		// in small segments
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+		let mut buf = BytesMut::with_capacity(0x500);
+		let header = Header::default();
+		let (_, r) = tokio::join!(
+			async {
+				for chunk in msg[..last].chunks(0x20).chain([&msg[last..]]) {
+					c.write_all(chunk).await.unwrap();
+					tokio::task::yield_now().await;
+				}
+			},
+			server_handshake(&mut s, &users, &mut buf, &header, false, &replay)
+		);
+		assert!(matches!(r, Some(Accept::Ok(..))));
+		assert_eq!(b"early data", &buf[..]);
+	}
+
+	// a read for each segment, writes are dropped
+	struct Segments(Vec<Vec<u8>>);
+
+	impl AsyncRead for Segments {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			_: &mut Context<'_>,
+			buf: &mut io::ReadBuf<'_>,
+		) -> Poll<io::Result<()>> {
+			if !self.0.is_empty() {
+				buf.put_slice(&self.0.remove(0));
+			}
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	impl AsyncWrite for Segments {
+		fn poll_write(
+			self: Pin<&mut Self>,
+			_: &mut Context<'_>,
+			buf: &[u8],
+		) -> Poll<io::Result<usize>> {
+			Poll::Ready(Ok(buf.len()))
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	#[tokio::test]
+	async fn test_split_at_header() {
+		init();
+
+		let psk = psk();
+		let users = users(&psk);
+
+		let mut msg = BytesMut::new();
+		let req = Req {
+			host: "example.com",
+			port: 443,
+			flags: 0,
+			salt: rand::random(),
+			time: now(),
+			early: &[],
+		};
+		write_msg(
+			&mut msg,
+			&psk.cipher,
+			&Header::parse("GET / HTTP/1.1", vec![]),
+			None,
+			psk.name.as_bytes(),
+			&req,
+		);
+		let eoh = eoh(&msg).unwrap() + EOH.len();
+
+		// the first or a later read ends right after the header
+		for segments in [
+			vec![msg[..eoh].to_vec(), msg[eoh..].to_vec()],
+			vec![msg[..4].to_vec(), msg[4..eoh].to_vec(), msg[eoh..].to_vec()],
+		] {
+			let mut s = Segments(segments);
+			let mut buf = BytesMut::with_capacity(0x500);
+			let r = server_handshake(
+				&mut s,
+				&users,
+				&mut buf,
+				&Header::default(),
+				false,
+				&Replay::new(60),
+			)
+			.await;
+			assert!(matches!(r, Some(Accept::Ok(..))));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_replayed() {
+		init();
+
+		let psk = psk();
+		let replay = Replay::new(60);
+		let users = users(&psk);
+
+		let mut msg = BytesMut::new();
+		let req = Req {
+			host: "example.com",
+			port: 443,
+			flags: 0,
+			salt: rand::random(),
+			time: now(),
+			early: &[],
+		};
+		write_msg(
+			&mut msg,
+			&psk.cipher,
+			&Header::default(),
+			None,
+			psk.name.as_bytes(),
+			&req,
+		);
+		for accepted in [true, false] {
+			let (mut c, mut s) = tokio::io::duplex(0x500);
+			c.write_all(&msg).await.unwrap();
+			let mut buf = BytesMut::with_capacity(0x500);
+			let r = server_handshake(&mut s, &users, &mut buf, &Header::default(), false, &replay)
+				.await;
+			assert_eq!(accepted, matches!(r, Some(Accept::Ok(..))));
+			assert_eq!(accepted, buf != msg);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_enc() {
+		init();
+
+		let psk = psk();
+		let (mut c, mut s) = sessions(&psk, false);
 
 		let mut buf = BytesMut::with_capacity(0x100);
 		let (mut b, mut a) = tokio::io::simplex(0x100);
-		let (mut d, mut c) = tokio::io::simplex(0x100);
+		let (mut d, mut c_w) = tokio::io::simplex(0x100);
 
 		let test_payload = b"you're (not) welcome.";
 		a.write_all(test_payload).await.unwrap();
 
-		enc1(&mut buf, &cipher, &mut c, &mut b).await.unwrap();
+		enc1(&mut buf, &mut c.tx, &mut c_w, &mut b).await.unwrap();
 
-		dec1(&mut buf, &cipher, &mut a, &mut d).await.unwrap();
+		dec1(&mut buf, &mut s.rx, &mut a, &mut d).await.unwrap();
 
 		buf.clear();
 		b.read_buf(&mut buf).await.unwrap();
 
 		assert_eq!(test_payload, &buf[..]);
+
+		// records can't be replayed
+		let mut record = BytesMut::new();
+		a.write_all(test_payload).await.unwrap();
+		enc1(&mut buf, &mut c.tx, &mut c_w, &mut b).await.unwrap();
+		d.read_buf(&mut record).await.unwrap();
+		let mut r = &record[..];
+		dec1(&mut buf, &mut s.rx, &mut a, &mut r).await.unwrap();
+		let mut r = &record[..];
+		assert!(dec1(&mut buf, &mut s.rx, &mut a, &mut r).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_padding() {
+		init();
+
+		let psk = psk();
+		let data = b"you're (not) welcome.";
+		for padding in [
+			Padding::None,
+			Padding::Random,
+			Padding::Bucket,
+			Padding::Http,
+		] {
+			let (c, mut s) = sessions(&psk, true);
+			let mut c = c.padding(padding);
+			let mut buf = BytesMut::with_capacity(0x1000);
+			let mut record = Vec::new();
+			enc1(&mut buf, &mut c.tx, &mut record, &mut &data[..])
+				.await
+				.unwrap();
+			let padded = record.len() - 4 - tag_size::<Cipher>();
+			match padding {
+				Padding::None => assert_eq!(data.len(), padded),
+				Padding::Bucket => assert_eq!(0x100, padded),
+				_ => assert!(padded >= data.len()),
+			}
+			let mut plain = Vec::new();
+			dec1(&mut buf, &mut s.rx, &mut plain, &mut &record[..])
+				.await
+				.unwrap();
+			assert_eq!(data, &plain[..]);
+		}
+
+		assert_eq!(0x1000, Padding::Bucket.len(0x401) + 0x401);
+		assert_eq!(SEGMENT * 2, Padding::Http.len(SEGMENT + 1) + SEGMENT + 1);
+		assert_eq!(0, Padding::Http.len(MAX_RECORD));
+	}
+
+	#[tokio::test]
+	async fn test_full() {
+		init();
+
+		let psk = psk();
+		let (c, mut s) = sessions(&psk, true);
+
+		let (mut p, mut p_peer) = tokio::io::duplex(0x100);
+		let (mut e, mut e_peer) = tokio::io::duplex(0x10000);
+
+		let mut wire = Vec::new();
+		tokio::join!(
+			async {
+				for i in 0..8u8 {
+					p_peer.write_all(&[i; 0x10]).await.unwrap();
+					tokio::task::yield_now().await;
+				}
+				p_peer.shutdown().await.unwrap();
+			},
+			duplex(c, &mut p, &mut e),
+			async {
+				e_peer.read_to_end(&mut wire).await.unwrap();
+				e_peer.shutdown().await.unwrap();
+			},
+		);
+
+		// nothing in plain on the wire
+		for i in 0..8u8 {
+			assert!(!wire.windows(0x10).any(|w| w == [i; 0x10]));
+		}
+
+		let mut buf = BytesMut::with_capacity(0x100);
+		let mut plain = Vec::new();
+		let mut r = &wire[..];
+		while dec1(&mut buf, &mut s.rx, &mut plain, &mut r)
+			.await
+			.is_some()
+		{}
+		let expected: Vec<u8> = (0..8u8).flat_map(|i| [i; 0x10]).collect();
+		assert_eq!(expected, plain);
+		// ended at the final record
+		assert!(r.is_empty());
 	}
 }
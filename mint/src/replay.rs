@@ -0,0 +1,102 @@
+// rejects replayed handshakes
+// a request carries a timestamp and a random salt,
+// timestamps off by more than the window are rejected,
+// salts seen within the window are rejected
+
+use std::{
+	cell::RefCell,
+	collections::{HashSet, VecDeque},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::*;
+
+use crate::proto::Salt;
+
+// way more than the handshakes we'd see in a window,
+// if it's reached anyway, the oldest are forgotten
+const MAX_ENTRIES: usize = 0x10000;
+
+pub struct Replay {
+	window: u64,
+	seen: RefCell<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+	salts: HashSet<Salt>,
+	// in the order of arrival, with when it can be forgotten
+	expiry: VecDeque<(u64, Salt)>,
+}
+
+pub fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+impl Replay {
+	pub fn new(window: u64) -> Self {
+		Self {
+			window,
+			seen: RefCell::default(),
+		}
+	}
+
+	pub fn check(&self, time: u64, salt: &Salt) -> bool {
+		self.check_at(now(), time, salt)
+	}
+
+	fn check_at(&self, now: u64, time: u64, salt: &Salt) -> bool {
+		if now.abs_diff(time) > self.window {
+			debug!("timestamp off by {}s, rejected", now.abs_diff(time));
+			return false;
+		}
+		let mut seen = self.seen.borrow_mut();
+		let seen = &mut *seen;
+		// a replay of it would be out of the window by then
+		while let Some((t, _)) = seen.expiry.front()
+			&& *t < now
+		{
+			let (_, s) = seen.expiry.pop_front().unwrap();
+			seen.salts.remove(&s);
+		}
+		if seen.salts.contains(salt) {
+			debug!("replayed handshake, rejected");
+			return false;
+		}
+		if seen.expiry.len() >= MAX_ENTRIES {
+			warn!("too many handshakes within the window, forgetting the oldest");
+			let (_, s) = seen.expiry.pop_front().unwrap();
+			seen.salts.remove(&s);
+		}
+		seen.salts.insert(*salt);
+		// time is at most now + window
+		seen.expiry.push_back((now + self.window * 2, *salt));
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_replay() {
+		let r = Replay::new(60);
+		let (a, b) = ([1u8; 32], [2u8; 32]);
+		let t = 1_000_000;
+
+		assert!(r.check_at(t, t, &a));
+		assert!(!r.check_at(t + 1, t, &a));
+		assert!(r.check_at(t + 1, t + 30, &b));
+		// out of the window
+		assert!(!r.check_at(t + 61, t, &[3u8; 32]));
+		assert!(!r.check_at(t, t + 61, &[3u8; 32]));
+		// forgotten, but still rejected by the timestamp
+		assert!(r.check_at(t + 200, t + 200, &[4u8; 32]));
+		assert_eq!(r.seen.borrow().salts.len(), 1);
+		assert!(!r.check_at(t + 200, t, &a));
+	}
+}
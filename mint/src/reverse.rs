@@ -0,0 +1,273 @@
+// services behind NAT, through agents dialing out to the server
+// an agent registers a service by name, with a session muxed the other way around,
+// streams to the service are opened by the server, with the name as the destination
+// the server exposes them on local ports, and to clients as <name>.mint
+
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	io,
+	pin::Pin,
+	rc::Rc,
+	task::{Context, Poll},
+};
+
+use log::*;
+use socks5::{
+	Acl, Addr, Bind, Dst, Limiter, Metered, Resolver, Scope, Stats, Timeouts, accept,
+	copy_bidirectional, listen, timeout,
+};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	net::TcpStream,
+};
+
+use crate::mux::{self, Incoming, Mux};
+
+// destinations of clients in it are services
+pub const DOMAIN: &str = ".mint";
+
+// by name, on the server
+#[derive(Default)]
+pub struct Services {
+	muxes: RefCell<HashMap<String, Rc<Mux>>>,
+	// the user a name was first registered by, until the server restarts
+	owners: RefCell<HashMap<String, Rc<str>>>,
+}
+
+impl Services {
+	// whether user can register name, it's theirs from then on
+	pub fn claim(&self, name: &str, user: &Rc<str>) -> bool {
+		let mut owners = self.owners.borrow_mut();
+		match owners.get(name) {
+			Some(owner) => owner == user,
+			None => {
+				owners.insert(name.to_owned(), user.clone());
+				true
+			}
+		}
+	}
+
+	// after claim, the latest agent of the owner takes over
+	pub fn register(&self, name: &str, mux: Rc<Mux>) {
+		self.muxes.borrow_mut().insert(name.to_owned(), mux);
+	}
+
+	// only if it's still the same agent
+	pub fn unregister(&self, name: &str, mux: &Rc<Mux>) {
+		let mut muxes = self.muxes.borrow_mut();
+		if muxes.get(name).is_some_and(|m| Rc::ptr_eq(m, mux)) {
+			muxes.remove(name);
+		}
+	}
+
+	pub async fn open(&self, name: &str) -> Option<mux::Stream> {
+		let mux = self.muxes.borrow().get(name).cloned();
+		let Some(mux) = mux else {
+			info!("service {name} not registered");
+			return None;
+		};
+		mux.open(&Dst {
+			addr: Addr::Domain(name),
+			port: 0,
+		})
+		.await
+	}
+}
+
+// name of the service dst is for, if it is
+pub fn service<'a>(dst: &'a Dst) -> Option<&'a str> {
+	match &dst.addr {
+		Addr::Domain(h) => h.strip_suffix(DOMAIN),
+		Addr::DomainOwned(h) => h.strip_suffix(DOMAIN),
+		_ => None,
+	}
+}
+
+// a destination, or a service
+pub enum Target {
+	Tcp(TcpStream),
+	Service(mux::Stream),
+}
+
+pub async fn connect(
+	services: &Services,
+	bind: Option<&Bind>,
+	dns: Option<Resolver>,
+	acl: &Acl,
+	dst: &Dst<'_>,
+) -> Option<Target> {
+	if let Some(name) = service(dst) {
+		return services.open(name).await.map(Target::Service);
+	}
+	let u = socks5::connect(bind, dns, acl, dst).await?;
+	let _ = u.set_nodelay(true);
+	Some(Target::Tcp(u))
+}
+
+// serves a service on addr
+pub async fn expose(
+	services: Rc<Services>,
+	name: String,
+	addr: String,
+	limiter: Rc<Limiter>,
+	timeouts: Timeouts,
+	stats: Rc<Stats>,
+) -> Option<()> {
+	let l = listen(&addr).await?;
+	info!("service {name} exposed on {addr}");
+	loop {
+		let (s, r_addr, permit) = accept(&l, &limiter).await;
+		let _ = s.set_nodelay(true);
+		let services = services.clone();
+		let name = name.clone();
+		let mut sess = stats.session();
+		sess.with(Scope::Client, r_addr.ip());
+		sess.with(Scope::Destination, format_args!("{name}{DOMAIN}"));
+		tokio::task::spawn_local(async move {
+			let _permit = permit;
+			info!("{r_addr} -> service {name}");
+			let Some(u) = services.open(&name).await else {
+				sess.fail();
+				return;
+			};
+			if copy_bidirectional(Metered::new(s, &sess), u, timeouts.idle())
+				.await
+				.is_none()
+			{
+				debug!("idle timeout or error: {r_addr} -> service {name}");
+			}
+		});
+	}
+}
+
+// on the agent, streams from the server to target, until the session is closed
+pub async fn forward(mut incoming: Incoming, target: &str, timeouts: Timeouts) {
+	while let Some((dst, stream)) = incoming.recv().await {
+		let target = target.to_owned();
+		tokio::task::spawn_local(async move {
+			debug!("service {dst} -> {target}");
+			let Some(u) = timeout(
+				format_args!("connecting to {target}"),
+				timeouts.connect(),
+				async {
+					TcpStream::connect(&target)
+						.await
+						.inspect_err(|e| error!("error connecting to {target}: {e}"))
+						.ok()
+				},
+			)
+			.await
+			else {
+				return;
+			};
+			let _ = u.set_nodelay(true);
+			if copy_bidirectional(u, stream, timeouts.idle())
+				.await
+				.is_none()
+			{
+				debug!("idle timeout or error: service {dst} -> {target}");
+			}
+		});
+	}
+}
+
+impl AsyncRead for Target {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Target::Tcp(io) => Pin::new(io).poll_read(cx, buf),
+			Target::Service(io) => Pin::new(io).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for Target {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Target::Tcp(io) => Pin::new(io).poll_write(cx, buf),
+			Target::Service(io) => Pin::new(io).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Target::Tcp(io) => Pin::new(io).poll_flush(cx),
+			Target::Service(io) => Pin::new(io).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Target::Tcp(io) => Pin::new(io).poll_shutdown(cx),
+			Target::Service(io) => Pin::new(io).poll_shutdown(cx),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		task::LocalSet,
+	};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_services() {
+		let dst: Dst = ("ssh.mint", 22).into();
+		assert_eq!(Some("ssh"), service(&dst));
+		let dst: Dst = ("example.com", 443).into();
+		assert_eq!(None, service(&dst));
+
+		LocalSet::new()
+			.run_until(async {
+				let services = Services::default();
+				assert!(services.open("ssh").await.is_none());
+
+				// the agent side of the mux, echoing
+				let (a, s) = tokio::io::duplex(0x1000);
+				let (_agent, mut incoming) = Mux::new(a, true);
+				let (mux, _) = Mux::new(s, false);
+				let (alice, bob): (Rc<str>, Rc<str>) = ("alice".into(), "bob".into());
+				assert!(services.claim("ssh", &alice));
+				services.register("ssh", mux.clone());
+				tokio::task::spawn_local(async move {
+					let (dst, mut s) = incoming.recv().await.unwrap();
+					assert_eq!("ssh:0", dst.to_string());
+					let mut buf = [0u8; 5];
+					s.read_exact(&mut buf).await.unwrap();
+					s.write_all(&buf).await.unwrap();
+				});
+				let mut s = services.open("ssh").await.unwrap();
+				s.write_all(b"hello").await.unwrap();
+				let mut buf = [0u8; 5];
+				s.read_exact(&mut buf).await.unwrap();
+				assert_eq!(b"hello", &buf);
+
+				// not by another user, even once it's gone
+				assert!(!services.claim("ssh", &bob));
+				assert!(services.claim("web", &bob));
+
+				// replaced by another agent of the same user, not removed by the old one
+				assert!(services.claim("ssh", &alice));
+				let (_, s) = tokio::io::duplex(0x1000);
+				let (other, _) = Mux::new(s, false);
+				services.register("ssh", other.clone());
+				services.unregister("ssh", &mux);
+				assert!(services.muxes.borrow().contains_key("ssh"));
+				services.unregister("ssh", &other);
+				assert!(services.open("ssh").await.is_none());
+				assert!(!services.claim("ssh", &bob));
+			})
+			.await;
+	}
+}
@@ -0,0 +1,247 @@
+// real TLS under mint, with a certificate on the server,
+// so it can share 443 with a web server, which gets the fallback, in plain text
+// the client verifies the server with public CAs, or against a pin, for a local certificate
+
+use std::{
+	io,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use log::*;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+	TlsAcceptor, TlsConnector, TlsStream,
+	rustls::{
+		CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig,
+		SignatureScheme,
+		client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+		crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+		pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+	},
+};
+
+// the certificate chain and the key, in PEM
+pub fn acceptor(cert: &str, key: &str, alpn: &[String]) -> Option<TlsAcceptor> {
+	let certs = CertificateDer::pem_file_iter(cert)
+		.and_then(|i| i.collect::<Result<Vec<_>, _>>())
+		.inspect_err(|e| error!("error reading certificates from \"{cert}\": {e}"))
+		.ok()?;
+	let Some(first) = certs.first() else {
+		error!("no certificate in \"{cert}\"");
+		return None;
+	};
+	info!("TLS certificate pin: {}", pin(first));
+	let key = PrivateKeyDer::from_pem_file(key)
+		.inspect_err(|e| error!("error reading private key from \"{key}\": {e}"))
+		.ok()?;
+	server_config(certs, key, alpn).map(|c| TlsAcceptor::from(Arc::new(c)))
+}
+
+fn server_config(
+	certs: Vec<CertificateDer<'static>>,
+	key: PrivateKeyDer<'static>,
+	alpn: &[String],
+) -> Option<ServerConfig> {
+	let mut config = ServerConfig::builder()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)
+		.inspect_err(|e| error!("invalid certificate or key: {e}"))
+		.ok()?;
+	config.alpn_protocols = alpn_protocols(alpn);
+	Some(config)
+}
+
+// pin is the SHA-256 of the server certificate in base64, public CAs are used if it's empty
+pub fn connector(alpn: &[String], pin: &str) -> Option<TlsConnector> {
+	let builder = ClientConfig::builder();
+	let mut config = if pin.is_empty() {
+		let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+		builder.with_root_certificates(roots).with_no_client_auth()
+	} else {
+		let pin = BASE64_STANDARD
+			.decode(pin)
+			.ok()
+			.and_then(|p| <[u8; 32]>::try_from(p).ok());
+		let Some(pin) = pin else {
+			error!("invalid TLS pin, should be a SHA-256 in base64");
+			return None;
+		};
+		builder
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(Pinned {
+				pin,
+				provider: ring::default_provider(),
+			}))
+			.with_no_client_auth()
+	};
+	config.alpn_protocols = alpn_protocols(alpn);
+	Some(TlsConnector::from(Arc::new(config)))
+}
+
+fn alpn_protocols(alpn: &[String]) -> Vec<Vec<u8>> {
+	alpn.iter()
+		.filter(|p| !p.is_empty())
+		.map(|p| p.as_bytes().to_vec())
+		.collect()
+}
+
+// of a certificate, to pin it on the client
+pub fn pin(cert: &CertificateDer) -> String {
+	BASE64_STANDARD.encode(Sha256::digest(cert))
+}
+
+// accepts only the certificate pinned, whoever it's issued by or to
+#[derive(Debug)]
+struct Pinned {
+	pin: [u8; 32],
+	provider: CryptoProvider,
+}
+
+impl ServerCertVerifier for Pinned {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, Error> {
+		if Sha256::digest(end_entity)[..] != self.pin {
+			error!("TLS certificate not pinned: {}", pin(end_entity));
+			return Err(Error::InvalidCertificate(
+				CertificateError::ApplicationVerificationFailure,
+			));
+		}
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, Error> {
+		verify_tls12_signature(
+			message,
+			cert,
+			dss,
+			&self.provider.signature_verification_algorithms,
+		)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, Error> {
+		verify_tls13_signature(
+			message,
+			cert,
+			dss,
+			&self.provider.signature_verification_algorithms,
+		)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.provider
+			.signature_verification_algorithms
+			.supported_schemes()
+	}
+}
+
+// a stream as is, or in TLS
+pub enum Stream<T> {
+	Plain(T),
+	Tls(Box<TlsStream<T>>),
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<T> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(io) => Pin::new(io).poll_read(cx, buf),
+			Stream::Tls(io) => Pin::new(io).poll_read(cx, buf),
+		}
+	}
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<T> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Stream::Plain(io) => Pin::new(io).poll_write(cx, buf),
+			Stream::Tls(io) => Pin::new(io).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(io) => Pin::new(io).poll_flush(cx),
+			Stream::Tls(io) => Pin::new(io).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(io) => Pin::new(io).poll_shutdown(cx),
+			Stream::Tls(io) => Pin::new(io).poll_shutdown(cx),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_tls() {
+		let cert = rcgen::generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+		let der = cert.cert.der().clone();
+		let key = PrivateKeyDer::try_from(cert.signing_key.serialize_der()).unwrap();
+		let alpn = vec!["http/1.1".to_owned()];
+		let acceptor = TlsAcceptor::from(Arc::new(
+			server_config(vec![der.clone()], key, &alpn).unwrap(),
+		));
+		let name = ServerName::try_from("example.com").unwrap();
+
+		// pinned, self-signed is fine
+		let tls = connector(&alpn, &pin(&der)).unwrap();
+		let (c, s) = duplex(0x1000);
+		let (c, s) = tokio::join!(tls.connect(name.clone(), c), acceptor.accept(s));
+		let (mut c, mut s) = (c.unwrap(), s.unwrap());
+		assert_eq!(Some(&b"http/1.1"[..]), c.get_ref().1.alpn_protocol());
+		c.write_all(b"hello").await.unwrap();
+		c.flush().await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(b"hello", &buf);
+
+		// not pinned
+		let other = BASE64_STANDARD.encode([0u8; 32]);
+		let tls = connector(&alpn, &other).unwrap();
+		let (c, s) = duplex(0x1000);
+		let (c, _) = tokio::join!(tls.connect(name.clone(), c), acceptor.accept(s));
+		assert!(c.is_err());
+
+		// not trusted by public CAs
+		let tls = connector(&alpn, "").unwrap();
+		let (c, s) = duplex(0x1000);
+		let (c, _) = tokio::join!(tls.connect(name, c), acceptor.accept(s));
+		assert!(c.is_err());
+
+		assert!(connector(&alpn, "invalid").is_none());
+	}
+}
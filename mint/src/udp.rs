@@ -0,0 +1,460 @@
+// datagrams over one full mode session, for SOCKS5 UDP ASSOCIATE
+// frames: 2 bytes length, the address in SOCKS5 format, data
+// to the server it's the destination, from the server it's the source
+// the server sends from a socket per address family, like a NAT,
+// only addresses sent to recently can send back
+
+use std::{
+	collections::{HashMap, HashSet},
+	future::poll_fn,
+	io,
+	net::{IpAddr, SocketAddr},
+	rc::Rc,
+	task::Poll,
+	time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::bytes::{Buf, BytesMut};
+use log::*;
+use socks5::{
+	Acl, Addr, Bind, Dst, Resolver, Session, bind_udp, lookup, read_dst, read_udp_header,
+	write_dst, write_udp_header,
+};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, split},
+	net::UdpSocket,
+	select,
+	sync::mpsc,
+	task::spawn_local,
+	time::interval,
+};
+
+const MAX_FRAME: usize = 0xffff;
+
+// max size of a datagram
+const MAX_DATAGRAM: usize = 0x10000;
+
+async fn put_frame(buf: &mut Vec<u8>, addr: &Dst<'_>, data: &[u8]) -> Option<()> {
+	buf.clear();
+	buf.extend_from_slice(&[0, 0]);
+	write_dst(buf, addr).await?;
+	buf.extend_from_slice(data);
+	let len = buf.len() - 2;
+	if len > MAX_FRAME {
+		debug!("datagram too large: {}", data.len());
+		return None;
+	}
+	buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+	Some(())
+}
+
+// a whole frame from the front of buf, if there's one
+fn take_frame(buf: &mut BytesMut) -> Option<BytesMut> {
+	let [a, b, ..] = buf[..] else {
+		return None;
+	};
+	let len = u16::from_be_bytes([a, b]) as usize;
+	if buf.len() < 2 + len {
+		return None;
+	}
+	buf.advance(2);
+	Some(buf.split_to(len))
+}
+
+// server side, the state of an association
+struct Nat<'a> {
+	bind: Option<&'a Bind>,
+	dns: Option<Resolver>,
+	acl: &'a Acl,
+	timeout: Duration,
+	v4: Option<UdpSocket>,
+	v6: Option<UdpSocket>,
+	// sent to, with when
+	peers: HashMap<SocketAddr, Instant>,
+	// so domains are not resolved for every datagram
+	resolved: HashMap<String, (SocketAddr, Instant)>,
+	// domains are resolved aside, so datagrams from peers aren't held up meanwhile
+	lookups: mpsc::UnboundedSender<(Dst<'static>, Vec<u8>)>,
+	resolving: HashSet<String>,
+}
+
+impl Nat<'_> {
+	async fn send(&mut self, frame: &[u8]) -> Option<()> {
+		let mut data = frame;
+		let dst = read_dst(&mut data).await?;
+		let addr = match &dst.addr {
+			Addr::V4(_) | Addr::V6(_) => {
+				lookup(self.bind, self.dns.clone(), self.acl, &dst).await?[0]
+			}
+			_ => {
+				let key = dst.to_string();
+				match self.resolved.get(&key) {
+					Some((a, t)) if t.elapsed() < self.timeout => *a,
+					// the first datagram is sent once it's resolved, dropped until then
+					_ if self.resolving.insert(key) => {
+						let _ = self.lookups.send((dst, data.to_vec()));
+						return Some(());
+					}
+					_ => {
+						debug!("datagram to {dst} while resolving it, dropped");
+						return None;
+					}
+				}
+			}
+		};
+		self.send_to(addr, data).await
+	}
+
+	// of a lookup, with the datagram waiting for it
+	async fn resolved(&mut self, key: String, addr: Option<SocketAddr>, data: &[u8]) -> Option<()> {
+		self.resolving.remove(&key);
+		let addr = addr?;
+		self.resolved.insert(key, (addr, Instant::now()));
+		self.send_to(addr, data).await
+	}
+
+	async fn send_to(&mut self, addr: SocketAddr, data: &[u8]) -> Option<()> {
+		let socket = if addr.is_ipv4() {
+			&mut self.v4
+		} else {
+			&mut self.v6
+		};
+		if socket.is_none() {
+			*socket = Some(bind_udp(self.bind, addr)?);
+		}
+		socket
+			.as_ref()
+			.unwrap()
+			.send_to(data, addr)
+			.await
+			.inspect_err(|e| debug!("error sending to {addr}: {e}"))
+			.ok()?;
+		self.peers.insert(addr, Instant::now());
+		Some(())
+	}
+
+	// from either socket
+	async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+		poll_fn(|cx| {
+			for s in [&self.v4, &self.v6].into_iter().flatten() {
+				let mut rb = ReadBuf::new(buf);
+				if let Poll::Ready(r) = s.poll_recv_from(cx, &mut rb) {
+					return Poll::Ready(r.map(|a| (rb.filled().len(), a)));
+				}
+			}
+			Poll::Pending
+		})
+		.await
+	}
+
+	fn allowed(&self, addr: &SocketAddr) -> bool {
+		self.peers
+			.get(addr)
+			.is_some_and(|t| t.elapsed() < self.timeout)
+	}
+
+	fn expire(&mut self) {
+		let timeout = self.timeout;
+		self.peers.retain(|_, t| t.elapsed() < timeout);
+		self.resolved.retain(|_, (_, t)| t.elapsed() < timeout);
+	}
+}
+
+// server side, io is the plain side of the session
+// ends when the client closes, or nothing is sent either way for timeout
+// should be run in a local set
+pub async fn serve<T: AsyncRead + AsyncWrite>(
+	io: T,
+	bind: Option<Bind>,
+	dns: Option<Resolver>,
+	acl: Rc<Acl>,
+	timeout: Duration,
+) {
+	let (lookups, mut queued) = mpsc::unbounded_channel::<(Dst<'static>, Vec<u8>)>();
+	let (done, mut results) = mpsc::unbounded_channel();
+	// each on its own, so a slow one doesn't hold up the others, ends once nat is gone
+	let resolver = {
+		let (bind, dns, acl) = (bind.clone(), dns.clone(), acl.clone());
+		async move {
+			while let Some((dst, data)) = queued.recv().await {
+				let (bind, dns, acl, done) = (bind.clone(), dns.clone(), acl.clone(), done.clone());
+				spawn_local(async move {
+					let addr = lookup(bind.as_ref(), dns, &acl, &dst).await.map(|a| a[0]);
+					let _ = done.send((dst.to_string(), addr, data));
+				});
+			}
+		}
+	};
+	let nat = Nat {
+		bind: bind.as_ref(),
+		dns,
+		acl: &acl,
+		timeout,
+		v4: None,
+		v6: None,
+		peers: HashMap::new(),
+		resolved: HashMap::new(),
+		lookups,
+		resolving: HashSet::new(),
+	};
+	tokio::join!(relay(io, nat, &mut results, timeout), resolver);
+}
+
+async fn relay<T: AsyncRead + AsyncWrite>(
+	io: T,
+	mut nat: Nat<'_>,
+	results: &mut mpsc::UnboundedReceiver<(String, Option<SocketAddr>, Vec<u8>)>,
+	timeout: Duration,
+) {
+	let (mut r, mut w) = split(io);
+	let mut rbuf = BytesMut::with_capacity(MAX_DATAGRAM);
+	let mut wbuf = Vec::with_capacity(MAX_DATAGRAM);
+	let mut dbuf = vec![0u8; MAX_DATAGRAM];
+	let mut last = Instant::now();
+	let mut tick = interval(timeout);
+	loop {
+		select! {
+			n = r.read_buf(&mut rbuf) => {
+				match n {
+					Ok(0) => break,
+					Ok(_) => {}
+					Err(e) => {
+						debug!("error reading datagrams: {e}");
+						break;
+					}
+				}
+				while let Some(frame) = take_frame(&mut rbuf) {
+					nat.send(&frame).await;
+				}
+				last = Instant::now();
+			}
+			Some((key, addr, data)) = results.recv() => {
+				nat.resolved(key, addr, &data).await;
+			}
+			r = nat.recv(&mut dbuf) => {
+				let (n, src) = match r {
+					Ok(r) => r,
+					Err(e) => {
+						debug!("error receiving datagrams: {e}");
+						continue;
+					}
+				};
+				if !nat.allowed(&src) {
+					debug!("datagram from {src} not sent to, dropped");
+					continue;
+				}
+				if put_frame(&mut wbuf, &Dst::from((src.ip(), src.port())), &dbuf[..n])
+					.await
+					.is_none()
+				{
+					continue;
+				}
+				if let Err(e) = w.write_all(&wbuf).await {
+					debug!("error writing datagrams: {e}");
+					break;
+				}
+				last = Instant::now();
+			}
+			_ = tick.tick() => {
+				if last.elapsed() >= timeout {
+					debug!("UDP association idle");
+					break;
+				}
+				nat.expire();
+			}
+		}
+	}
+	let _ = w.shutdown().await;
+}
+
+// client side, between the app's datagrams on socket, only from its ip, and the session on io
+// control is the SOCKS5 connection, the association ends with it
+pub async fn client<C: AsyncRead + Unpin, T: AsyncRead + AsyncWrite>(
+	socket: UdpSocket,
+	app: IpAddr,
+	control: &mut C,
+	io: T,
+	sess: &Session,
+) {
+	let (mut r, mut w) = split(io);
+	let mut rbuf = BytesMut::with_capacity(MAX_DATAGRAM);
+	let mut wbuf = Vec::with_capacity(MAX_DATAGRAM);
+	let mut dbuf = vec![0u8; MAX_DATAGRAM];
+	let mut cbuf = [0u8; 1];
+	// where the app sends from
+	let mut peer = None;
+	loop {
+		select! {
+			r = socket.recv_from(&mut dbuf) => {
+				let (n, src) = match r {
+					Ok(r) => r,
+					Err(e) => {
+						debug!("error receiving datagrams: {e}");
+						continue;
+					}
+				};
+				if src.ip() != app {
+					debug!("datagram from {src}, not the client, dropped");
+					continue;
+				}
+				peer = Some(src);
+				let mut data = &dbuf[..n];
+				let Some(dst) = read_udp_header(&mut data).await else {
+					continue;
+				};
+				if put_frame(&mut wbuf, &dst, data).await.is_none() {
+					continue;
+				}
+				if let Err(e) = w.write_all(&wbuf).await {
+					debug!("error writing datagrams: {e}");
+					break;
+				}
+				sess.count(n as u64, 0);
+			}
+			n = r.read_buf(&mut rbuf) => {
+				match n {
+					Ok(0) => break,
+					Ok(_) => {}
+					Err(e) => {
+						debug!("error reading datagrams: {e}");
+						break;
+					}
+				}
+				while let Some(frame) = take_frame(&mut rbuf) {
+					let mut data = &frame[..];
+					let (Some(src), Some(peer)) = (read_dst(&mut data).await, peer) else {
+						continue;
+					};
+					wbuf.clear();
+					if write_udp_header(&mut wbuf, &src).await.is_none() {
+						continue;
+					}
+					wbuf.extend_from_slice(data);
+					if let Err(e) = socket.send_to(&wbuf, peer).await {
+						debug!("error sending to {peer}: {e}");
+					}
+					sess.count(0, wbuf.len() as u64);
+				}
+			}
+			_ = control.read(&mut cbuf) => {
+				debug!("SOCKS5 connection closed, so is the association");
+				break;
+			}
+		}
+	}
+	let _ = w.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_frames() {
+		let mut buf = Vec::new();
+		let dst: Dst = ("example.com", 53).into();
+		put_frame(&mut buf, &dst, b"hello").await.unwrap();
+		let mut r = BytesMut::from(&buf[..]);
+		r.extend_from_slice(&buf[..3]);
+		let frame = take_frame(&mut r).unwrap();
+		let mut data = &frame[..];
+		assert_eq!(
+			dst.to_string(),
+			read_dst(&mut data).await.unwrap().to_string()
+		);
+		assert_eq!(b"hello", data);
+		// incomplete
+		assert!(take_frame(&mut r).is_none());
+		assert_eq!(3, r.len());
+
+		assert!(put_frame(&mut buf, &dst, &[0; MAX_FRAME]).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_resolving() {
+		let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let acl = Acl::default();
+		let (lookups, mut queued) = mpsc::unbounded_channel();
+		let mut nat = Nat {
+			bind: None,
+			dns: None,
+			acl: &acl,
+			timeout: Duration::from_secs(1),
+			v4: None,
+			v6: None,
+			peers: HashMap::new(),
+			resolved: HashMap::new(),
+			lookups,
+			resolving: HashSet::new(),
+		};
+		// queued, not waited for, more to it are dropped until it's done
+		let mut frame = Vec::new();
+		let dst: Dst = ("echo.test", 53).into();
+		for data in [&b"first"[..], b"second"] {
+			put_frame(&mut frame, &dst, data).await.unwrap();
+			nat.send(&frame[2..]).await;
+		}
+		let (dst, data) = queued.recv().await.unwrap();
+		assert_eq!("echo.test:53", dst.to_string());
+		assert_eq!(b"first", &data[..]);
+		assert!(queued.try_recv().is_err());
+
+		let addr = echo.local_addr().unwrap();
+		nat.resolved(dst.to_string(), Some(addr), &data)
+			.await
+			.unwrap();
+		// from then on, without a lookup
+		put_frame(&mut frame, &dst, b"third").await.unwrap();
+		nat.send(&frame[2..]).await.unwrap();
+		assert!(queued.try_recv().is_err());
+		let mut dbuf = [0u8; 0x100];
+		for expected in [&b"first"[..], b"third"] {
+			let (n, _) = echo.recv_from(&mut dbuf).await.unwrap();
+			assert_eq!(expected, &dbuf[..n]);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_serve() {
+		let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let echo_addr = echo.local_addr().unwrap();
+		let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let acl = Rc::new(Acl::default());
+		let (c, s) = duplex(0x10000);
+		let (mut r, mut w) = split(c);
+		tokio::join!(serve(s, None, None, acl, Duration::from_secs(1)), async {
+			let mut buf = Vec::new();
+			put_frame(
+				&mut buf,
+				&Dst::from((echo_addr.ip(), echo_addr.port())),
+				b"ping",
+			)
+			.await
+			.unwrap();
+			w.write_all(&buf).await.unwrap();
+			let mut dbuf = [0u8; 0x100];
+			let (n, from) = echo.recv_from(&mut dbuf).await.unwrap();
+			assert_eq!(b"ping", &dbuf[..n]);
+			// not sent to, dropped
+			other.send_to(b"nope", from).await.unwrap();
+			echo.send_to(b"pong", from).await.unwrap();
+
+			let mut rbuf = BytesMut::new();
+			let frame = loop {
+				if let Some(f) = take_frame(&mut rbuf) {
+					break f;
+				}
+				assert_ne!(0, r.read_buf(&mut rbuf).await.unwrap());
+			};
+			let mut data = &frame[..];
+			let src = read_dst(&mut data).await.unwrap();
+			assert_eq!(echo_addr.to_string(), src.to_string());
+			assert_eq!(b"pong", data);
+			// ends when idle
+			assert_eq!(0, r.read_buf(&mut rbuf).await.unwrap());
+			assert!(rbuf.is_empty());
+		});
+	}
+}
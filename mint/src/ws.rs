@@ -0,0 +1,596 @@
+// the stream in WebSocket binary frames, after a genuine HTTP/1.1 upgrade,
+// so it goes through HTTP aware proxies, like nginx
+// a write is a frame, masked from the client, as required
+// closing is a close frame, not echoed back, the other direction goes on until its own
+
+use std::{
+	io,
+	pin::Pin,
+	task::{Context, Poll, ready},
+};
+
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use chacha20poly1305::aead::bytes::{Buf, BufMut, BytesMut};
+use log::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind, ReadBuf};
+
+use crate::fake::{UA, field, ws_accept};
+
+const EOH: &[u8] = b"\r\n\r\n";
+
+// max size of the HTTP request or response
+const MAX_HEAD: usize = 0x2000;
+
+// max payload of a frame written, larger writes are split
+const MAX_FRAME: usize = 0x10000;
+
+// how much is read at once from the underlying stream
+const READ_SIZE: usize = 0x4000;
+
+const OP_CONTINUATION: u8 = 0;
+const OP_TEXT: u8 = 1;
+const OP_BINARY: u8 = 2;
+const OP_CLOSE: u8 = 8;
+const OP_PING: u8 = 9;
+const OP_PONG: u8 = 10;
+
+// the client side of the upgrade, with one of the hosts
+pub async fn connect<T: AsyncRead + AsyncWrite + Unpin>(
+	mut io: T,
+	path: &str,
+	hosts: &[String],
+) -> Option<Ws<T>> {
+	let host = &hosts[rand::random_range(0..hosts.len())];
+	let key = BASE64_STANDARD.encode(rand::random::<[u8; 16]>());
+	let req = format!(
+		"GET {path} HTTP/1.1\r\nHost: {host}\r\n{UA}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+	);
+	io.write_all(req.as_bytes())
+		.await
+		.inspect_err(|e| debug!("WebSocket error writing: {e}"))
+		.ok()?;
+
+	let mut buf = BytesMut::with_capacity(0x400);
+	let Some(len) = read_head(&mut io, &mut buf)
+		.await
+		.inspect_err(|e| debug!("WebSocket error reading: {e}"))
+		.ok()?
+	else {
+		error!("invalid WebSocket response");
+		return None;
+	};
+	let head = buf.split_to(len);
+	let head = str::from_utf8(&head).unwrap_or_default();
+	let status = head.lines().next().unwrap_or_default();
+	if !status.starts_with("HTTP/1.1 101") {
+		error!("WebSocket upgrade refused: {status}");
+		return None;
+	}
+	if field(head, "sec-websocket-accept") != Some(&ws_accept(&key)) {
+		error!("invalid Sec-WebSocket-Accept");
+		return None;
+	}
+	Some(Ws::new(io, buf, true))
+}
+
+pub enum Upgrade<T> {
+	Ok(Ws<T>),
+	// not a WebSocket upgrade to the path, buf is left with everything read so far
+	Invalid(T),
+}
+
+// the server side of the upgrade, only to path
+pub async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+	mut io: T,
+	path: &str,
+	buf: &mut BytesMut,
+) -> Option<Upgrade<T>> {
+	let Some(len) = read_head(&mut io, buf)
+		.await
+		.inspect_err(|e| debug!("WebSocket error reading: {e}"))
+		.ok()?
+	else {
+		return Some(Upgrade::Invalid(io));
+	};
+	let Some(key) = upgrade_key(&buf[..len], path) else {
+		debug!("not a WebSocket upgrade to {path}");
+		return Some(Upgrade::Invalid(io));
+	};
+	let resp = format!(
+		"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+		ws_accept(&key)
+	);
+	io.write_all(resp.as_bytes())
+		.await
+		.inspect_err(|e| debug!("WebSocket error writing: {e}"))
+		.ok()?;
+	// anything after it is already in frames
+	buf.advance(len);
+	Some(Upgrade::Ok(Ws::new(io, buf.split(), false)))
+}
+
+// Sec-WebSocket-Key of a valid upgrade request to path
+fn upgrade_key(head: &[u8], path: &str) -> Option<String> {
+	let head = str::from_utf8(head).ok()?;
+	let mut req = head.lines().next()?.split(' ');
+	let (Some("GET"), Some(target), Some("HTTP/1.1")) = (req.next(), req.next(), req.next()) else {
+		return None;
+	};
+	// the query is not part of it
+	if target.split('?').next() != Some(path) {
+		return None;
+	}
+	// proxies may send it in lower case, or along with others
+	let has = |name, token: &str| {
+		field(head, name)
+			.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+	};
+	if !has("upgrade", "websocket") || !has("connection", "upgrade") {
+		return None;
+	}
+	if field(head, "sec-websocket-version") != Some("13") {
+		return None;
+	}
+	field(head, "sec-websocket-key").map(str::to_owned)
+}
+
+// reads until buf has the whole HTTP head, anything after it is left in buf
+// Ok(None) if it's not one
+async fn read_head<T: AsyncRead + Unpin>(
+	io: &mut T,
+	buf: &mut BytesMut,
+) -> io::Result<Option<usize>> {
+	buf.clear();
+	loop {
+		if io.read_buf(buf).await? == 0 {
+			if buf.is_empty() {
+				return Err(ErrorKind::UnexpectedEof.into());
+			}
+			debug!("EOF before a whole HTTP head");
+			return Ok(None);
+		}
+		if let Some(eoh) = buf.windows(EOH.len()).position(|w| w == EOH) {
+			return Ok(Some(eoh + EOH.len()));
+		}
+		if buf.len() >= MAX_HEAD {
+			debug!("EoH not found, unexpected");
+			return Ok(None);
+		}
+	}
+}
+
+pub struct Ws<T> {
+	io: T,
+	// frames written are masked
+	client: bool,
+	// read but not yet taken out of frames
+	rbuf: BytesMut,
+	// of the data frame being read
+	remaining: u64,
+	mask: Option<[u8; 4]>,
+	mask_pos: usize,
+	// a close frame is read
+	eof: bool,
+	// frames to be written
+	wbuf: BytesMut,
+	// data of the write framed in wbuf, if it isn't written out yet
+	pending: BytesMut,
+	close_sent: bool,
+}
+
+// of a frame
+struct Head {
+	len: usize,
+	opcode: u8,
+	payload: u64,
+	mask: Option<[u8; 4]>,
+}
+
+impl<T> Ws<T> {
+	// rbuf is anything read after the upgrade
+	fn new(io: T, rbuf: BytesMut, client: bool) -> Self {
+		Self {
+			io,
+			client,
+			rbuf,
+			remaining: 0,
+			mask: None,
+			mask_pos: 0,
+			eof: false,
+			wbuf: BytesMut::new(),
+			pending: BytesMut::new(),
+			close_sent: false,
+		}
+	}
+
+	fn put_frame(&mut self, opcode: u8, data: &[u8]) {
+		let buf = &mut self.wbuf;
+		buf.put_u8(0x80 | opcode);
+		let mask_bit = if self.client { 0x80 } else { 0 };
+		match data.len() {
+			n if n < 126 => buf.put_u8(mask_bit | n as u8),
+			n if n <= 0xffff => {
+				buf.put_u8(mask_bit | 126);
+				buf.put_u16(n as u16);
+			}
+			n => {
+				buf.put_u8(mask_bit | 127);
+				buf.put_u64(n as u64);
+			}
+		}
+		let offset = buf.len();
+		buf.put_slice(data);
+		if self.client {
+			let key: [u8; 4] = rand::random();
+			buf[offset..]
+				.iter_mut()
+				.enumerate()
+				.for_each(|(i, b)| *b ^= key[i % 4]);
+			// the key goes before the payload
+			let payload = buf.split_off(offset);
+			buf.put_slice(&key);
+			buf.unsplit(payload);
+		}
+	}
+}
+
+fn parse_head(buf: &[u8]) -> Option<Head> {
+	let [b0, b1, ..] = *buf else {
+		return None;
+	};
+	let (mut len, payload) = match b1 & 0x7f {
+		126 => (
+			4,
+			u16::from_be_bytes(buf.get(2..4)?.try_into().unwrap()) as u64,
+		),
+		127 => (10, u64::from_be_bytes(buf.get(2..10)?.try_into().unwrap())),
+		n => (2, n as u64),
+	};
+	let mask = if b1 & 0x80 != 0 {
+		len += 4;
+		Some(buf.get(len - 4..len)?.try_into().unwrap())
+	} else {
+		None
+	};
+	Some(Head {
+		len,
+		opcode: b0 & 0x0f,
+		payload,
+		mask,
+	})
+}
+
+impl<T: AsyncWrite + Unpin> Ws<T> {
+	// writes out frames in wbuf
+	fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while !self.wbuf.is_empty() {
+			let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.wbuf))?;
+			if n == 0 {
+				return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+			}
+			self.wbuf.advance(n);
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for Ws<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = &mut *self;
+		loop {
+			if this.eof || buf.remaining() == 0 {
+				return Poll::Ready(Ok(()));
+			}
+			if this.remaining > 0 && !this.rbuf.is_empty() {
+				let n = (this.remaining.min(this.rbuf.len() as u64) as usize).min(buf.remaining());
+				let mut data = this.rbuf.split_to(n);
+				if let Some(mask) = this.mask {
+					for (i, b) in data.iter_mut().enumerate() {
+						*b ^= mask[(this.mask_pos + i) % 4];
+					}
+					this.mask_pos += n;
+				}
+				buf.put_slice(&data);
+				this.remaining -= n as u64;
+				return Poll::Ready(Ok(()));
+			}
+			if this.remaining == 0
+				&& let Some(head) = parse_head(&this.rbuf)
+			{
+				match head.opcode {
+					OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+						this.rbuf.advance(head.len);
+						this.remaining = head.payload;
+						this.mask = head.mask;
+						this.mask_pos = 0;
+						continue;
+					}
+					OP_CLOSE | OP_PING | OP_PONG => {
+						if head.payload > 125 {
+							return Poll::Ready(Err(io::Error::new(
+								ErrorKind::InvalidData,
+								"WebSocket control frame too long",
+							)));
+						}
+						let end = head.len + head.payload as usize;
+						if this.rbuf.len() >= end {
+							let mut payload = this.rbuf.split_to(end).split_off(head.len);
+							if let Some(mask) = head.mask {
+								payload
+									.iter_mut()
+									.enumerate()
+									.for_each(|(i, b)| *b ^= mask[i % 4]);
+							}
+							match head.opcode {
+								OP_CLOSE => {
+									debug!("WebSocket closed by the peer");
+									this.eof = true;
+								}
+								OP_PING => {
+									this.put_frame(OP_PONG, &payload);
+									// best effort, it's written along with the next frame otherwise
+									if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+										return Poll::Ready(Err(e));
+									}
+								}
+								_ => {}
+							}
+							continue;
+						}
+					}
+					op => {
+						return Poll::Ready(Err(io::Error::new(
+							ErrorKind::InvalidData,
+							format!("unknown WebSocket opcode {op}"),
+						)));
+					}
+				}
+			}
+			// more is needed
+			let len = this.rbuf.len();
+			this.rbuf.resize(len + READ_SIZE, 0);
+			let mut rb = ReadBuf::new(&mut this.rbuf[len..]);
+			let r = Pin::new(&mut this.io).poll_read(cx, &mut rb);
+			let n = rb.filled().len();
+			this.rbuf.truncate(len + n);
+			ready!(r)?;
+			if n == 0 {
+				if this.remaining == 0 && this.rbuf.is_empty() {
+					debug!("WebSocket closed without a close frame");
+					this.eof = true;
+					continue;
+				}
+				return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+			}
+		}
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Ws<T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = &mut *self;
+		// it's taken as written once the whole frame is,
+		// a retry has to start with the same data, it can't be taken back
+		if !this.pending.is_empty() {
+			if !buf.starts_with(&this.pending) {
+				return Poll::Ready(Err(io::Error::new(
+					ErrorKind::InvalidInput,
+					"WebSocket write retried with other data",
+				)));
+			}
+			ready!(this.poll_drain(cx))?;
+			let n = this.pending.len();
+			this.pending.clear();
+			return Poll::Ready(Ok(n));
+		}
+		ready!(this.poll_drain(cx))?;
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
+		let n = buf.len().min(MAX_FRAME);
+		this.put_frame(OP_BINARY, &buf[..n]);
+		if this.poll_drain(cx)?.is_pending() {
+			this.pending.extend_from_slice(&buf[..n]);
+			return Poll::Pending;
+		}
+		Poll::Ready(Ok(n))
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		ready!(self.poll_drain(cx))?;
+		Pin::new(&mut self.io).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		if !self.close_sent {
+			self.close_sent = true;
+			self.put_frame(OP_CLOSE, &[]);
+		}
+		ready!(self.poll_drain(cx))?;
+		Pin::new(&mut self.io).poll_shutdown(cx)
+	}
+}
+
+// a stream as is, or in WebSocket frames
+pub enum Transport<T> {
+	Raw(T),
+	Ws(Ws<T>),
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for Transport<T> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Transport::Raw(io) => Pin::new(io).poll_read(cx, buf),
+			Transport::Ws(io) => Pin::new(io).poll_read(cx, buf),
+		}
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Transport<T> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Transport::Raw(io) => Pin::new(io).poll_write(cx, buf),
+			Transport::Ws(io) => Pin::new(io).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Transport::Raw(io) => Pin::new(io).poll_flush(cx),
+			Transport::Ws(io) => Pin::new(io).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Transport::Raw(io) => Pin::new(io).poll_shutdown(cx),
+			Transport::Ws(io) => Pin::new(io).poll_shutdown(cx),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{duplex, split};
+
+	use super::*;
+
+	fn hosts() -> Vec<String> {
+		vec!["example.com".into()]
+	}
+
+	#[tokio::test]
+	async fn test_ws() {
+		let (c, s) = duplex(0x100);
+		let hosts = hosts();
+		let (c, s) = tokio::join!(connect(c, "/ws", &hosts), async {
+			let mut buf = BytesMut::new();
+			match accept(s, "/ws", &mut buf).await {
+				Some(Upgrade::Ok(s)) => s,
+				_ => panic!("upgrade failed"),
+			}
+		});
+		let (mut c, mut s) = (c.unwrap(), s);
+
+		// larger than a frame, read in small pieces
+		let data: Vec<u8> = (0..MAX_FRAME * 2 + 0x123).map(|i| i as u8).collect();
+		let (_, echoed) = tokio::join!(
+			async {
+				let (mut r, mut w) = split(&mut s);
+				tokio::io::copy(&mut r, &mut w).await.unwrap();
+				w.shutdown().await.unwrap();
+			},
+			async {
+				let (mut r, mut w) = split(&mut c);
+				let (_, echoed) = tokio::join!(
+					async {
+						w.write_all(&data).await.unwrap();
+						w.shutdown().await.unwrap();
+					},
+					async {
+						let mut v = Vec::new();
+						r.read_to_end(&mut v).await.unwrap();
+						v
+					}
+				);
+				echoed
+			}
+		);
+		assert!(echoed == data);
+	}
+
+	#[tokio::test]
+	async fn test_frames() {
+		let (c, mut s) = duplex(0x1000);
+		let mut c = Ws::new(c, BytesMut::new(), true);
+		c.write_all(b"hello").await.unwrap();
+		let mut raw = [0u8; 2 + 4 + 5];
+		s.read_exact(&mut raw).await.unwrap();
+		// final binary frame, masked
+		assert_eq!([0x82, 0x80 | 5], raw[..2]);
+		let unmasked: Vec<u8> = raw[6..]
+			.iter()
+			.enumerate()
+			.map(|(i, b)| b ^ raw[2 + i % 4])
+			.collect();
+		assert_eq!(b"hello", &unmasked[..]);
+
+		// pings are answered, text and continuation frames are data too
+		s.write_all(&[0x89, 2, b'h', b'i', 0x01, 2, b'a', b'b', 0x80, 1, b'c'])
+			.await
+			.unwrap();
+		let mut buf = [0u8; 3];
+		c.read_exact(&mut buf).await.unwrap();
+		assert_eq!(b"abc", &buf);
+		let mut pong = [0u8; 2 + 4 + 2];
+		s.read_exact(&mut pong).await.unwrap();
+		assert_eq!([0x8a, 0x80 | 2], pong[..2]);
+
+		// a close frame is EOF
+		s.write_all(&[0x88, 0]).await.unwrap();
+		let mut v = Vec::new();
+		c.read_to_end(&mut v).await.unwrap();
+		assert!(v.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_retried_write() {
+		let (c, mut s) = duplex(0x10);
+		let mut c = Ws::new(c, BytesMut::new(), true);
+		let data = [1u8; 0x20];
+		let mut cx = Context::from_waker(std::task::Waker::noop());
+		assert!(Pin::new(&mut c).poll_write(&mut cx, &data).is_pending());
+		// not for other data, or less of it
+		for other in [&[2u8; 0x20][..], &data[..0x10]] {
+			let r = Pin::new(&mut c).poll_write(&mut cx, other);
+			assert!(matches!(r, Poll::Ready(Err(e)) if e.kind() == ErrorKind::InvalidInput));
+		}
+		let more = [&data[..], b"more"].concat();
+		let mut raw = vec![0u8; 2 + 4 + data.len()];
+		let (n, _) = tokio::join!(
+			std::future::poll_fn(|cx| Pin::new(&mut c).poll_write(cx, &more)),
+			s.read_exact(&mut raw)
+		);
+		assert_eq!(data.len(), n.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_invalid() {
+		for req in [
+			&b"GET /other HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"[..],
+			b"GET /ws HTTP/1.1\r\nHost: example.com\r\n\r\n",
+			b"POST /ws HTTP/1.1\r\n\r\n",
+		] {
+			let (mut c, s) = duplex(0x1000);
+			c.write_all(req).await.unwrap();
+			let mut buf = BytesMut::new();
+			let r = accept(s, "/ws", &mut buf).await;
+			assert!(matches!(r, Some(Upgrade::Invalid(..))));
+			// for the fallback
+			assert_eq!(req, &buf[..]);
+		}
+		assert_eq!(
+			Some("dGhlIHNhbXBsZSBub25jZQ==".into()),
+			upgrade_key(
+				b"GET /ws?a=b HTTP/1.1\r\nupgrade: WebSocket\r\nconnection: keep-alive, upgrade\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\nsec-websocket-version: 13\r\n\r\n",
+				"/ws"
+			)
+		);
+	}
+}
@@ -0,0 +1,309 @@
+// access control by client source address and by destination address/port
+// the most specific matching rule wins, deny wins a tie, nothing matches means allowed
+// so allow lists are exceptions, for an allow-only policy, deny "all" first
+
+use std::{
+	fmt::Display,
+	net::{IpAddr, SocketAddr},
+	str::FromStr,
+};
+
+use log::*;
+
+// keywords in lists
+const ALL: &[&str] = &["0.0.0.0/0", "::/0"];
+const PRIVATE: &[&str] = &[
+	"0.0.0.0/8",
+	"10.0.0.0/8",
+	"100.64.0.0/10",
+	"127.0.0.0/8",
+	"169.254.0.0/16",
+	"172.16.0.0/12",
+	"192.168.0.0/16",
+	"224.0.0.0/3",
+	"::/127",
+	"fc00::/7",
+	"fe80::/10",
+	"ff00::/8",
+];
+
+#[derive(clap::Args, Clone)]
+pub struct AclArgs {
+	/// allowed client addresses, comma separated CIDRs, or "private", "all",
+	/// if any, other clients are denied
+	#[arg(long, env, default_value = "")]
+	pub src_allow: String,
+
+	/// denied client addresses
+	#[arg(long, env, default_value = "")]
+	pub src_deny: String,
+
+	/// allowed destination addresses
+	#[arg(long, env, default_value = "")]
+	pub dst_allow: String,
+
+	/// denied destination addresses, the default depends on the server
+	#[arg(long, env)]
+	pub dst_deny: Option<String>,
+
+	/// allowed destination ports, comma separated, ranges like 1000-2000, or "all"
+	#[arg(long, env, default_value = "")]
+	pub dst_ports_allow: String,
+
+	/// denied destination ports
+	#[arg(long, env, default_value = "")]
+	pub dst_ports_deny: String,
+}
+
+impl AclArgs {
+	pub fn parse(&self, default_dst_deny: &str) -> Option<Acl> {
+		// an allow list of clients is of the only ones allowed
+		let mut src = Rules::parse(&self.src_allow, &self.src_deny)?;
+		src.default_deny = !src.allow.is_empty();
+		Some(Acl {
+			src,
+			dst: Rules::parse(
+				&self.dst_allow,
+				self.dst_deny.as_deref().unwrap_or(default_dst_deny),
+			)?,
+			ports: Rules::parse(&self.dst_ports_allow, &self.dst_ports_deny)?,
+		})
+	}
+}
+
+#[derive(Default)]
+pub struct Acl {
+	src: Rules<Cidr>,
+	dst: Rules<Cidr>,
+	ports: Rules<Ports>,
+}
+
+impl Acl {
+	pub fn check_src(&self, ip: IpAddr) -> bool {
+		self.src.check(&ip.to_canonical())
+	}
+
+	pub fn check_dst(&self, addr: SocketAddr) -> bool {
+		self.dst.check(&addr.ip().to_canonical())
+	}
+
+	pub fn check_port(&self, port: u16) -> bool {
+		self.ports.check(&port)
+	}
+}
+
+trait Rule: FromStr {
+	type Item;
+	const KEYWORDS: &[(&str, &[&str])];
+	// returns specificity if matches, larger is more specific
+	fn matches(&self, v: &Self::Item) -> Option<u32>;
+}
+
+struct Rules<T> {
+	allow: Vec<T>,
+	deny: Vec<T>,
+	// of what neither matches
+	default_deny: bool,
+}
+
+impl<T> Default for Rules<T> {
+	fn default() -> Self {
+		Self {
+			allow: Vec::new(),
+			deny: Vec::new(),
+			default_deny: false,
+		}
+	}
+}
+
+impl<T: Rule<Err: Display>> Rules<T> {
+	fn parse(allow: &str, deny: &str) -> Option<Self> {
+		Some(Self {
+			allow: parse_list(allow)?,
+			deny: parse_list(deny)?,
+			default_deny: false,
+		})
+	}
+
+	fn check(&self, v: &T::Item) -> bool {
+		let best = |l: &[T]| l.iter().filter_map(|r| r.matches(v)).max();
+		match (best(&self.allow), best(&self.deny)) {
+			(Some(a), Some(d)) => a > d,
+			(Some(_), None) => true,
+			(None, Some(_)) => false,
+			(None, None) => !self.default_deny,
+		}
+	}
+}
+
+fn parse_list<T: Rule<Err: Display>>(s: &str) -> Option<Vec<T>> {
+	let mut v = Vec::new();
+	for i in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+		if let Some((_, l)) = T::KEYWORDS.iter().find(|k| i.eq_ignore_ascii_case(k.0)) {
+			v.extend(l.iter().filter_map(|p| p.parse().ok()));
+			continue;
+		}
+		v.push(
+			i.parse()
+				.inspect_err(|e| error!("invalid rule \"{i}\": {e}"))
+				.ok()?,
+		);
+	}
+	Some(v)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+	addr: IpAddr,
+	len: u8,
+}
+
+impl FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, len) = match s.split_once('/') {
+			Some((a, l)) => (a, Some(l)),
+			None => (s, None),
+		};
+		let addr = IpAddr::from_str(addr).map_err(|e| e.to_string())?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let len = match len {
+			Some(l) => l
+				.parse()
+				.map_err(|_| format!("invalid prefix length {l}"))?,
+			None => max,
+		};
+		if len > max {
+			return Err(format!("invalid prefix length {len}"));
+		}
+		Ok(Self { addr, len })
+	}
+}
+
+impl Rule for Cidr {
+	type Item = IpAddr;
+	const KEYWORDS: &[(&str, &[&str])] = &[("all", ALL), ("private", PRIVATE)];
+
+	fn matches(&self, ip: &IpAddr) -> Option<u32> {
+		let m = match (self.addr, ip) {
+			(IpAddr::V4(a), IpAddr::V4(b)) => {
+				let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+				u32::from(a) & mask == u32::from(*b) & mask
+			}
+			(IpAddr::V6(a), IpAddr::V6(b)) => {
+				let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+				u128::from(a) & mask == u128::from(*b) & mask
+			}
+			_ => false,
+		};
+		m.then_some(self.len as u32)
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ports(u16, u16);
+
+impl FromStr for Ports {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let p = |p: &str| {
+			p.parse::<u16>()
+				.map_err(|e| format!("invalid port {p}: {e}"))
+		};
+		let r = match s.split_once('-') {
+			Some((a, b)) => Ports(p(a)?, p(b)?),
+			None => Ports(p(s)?, p(s)?),
+		};
+		if r.0 > r.1 {
+			return Err(format!("invalid port range {s}"));
+		}
+		Ok(r)
+	}
+}
+
+impl Rule for Ports {
+	type Item = u16;
+	const KEYWORDS: &[(&str, &[&str])] = &[("all", &["0-65535"])];
+
+	fn matches(&self, port: &u16) -> Option<u32> {
+		// narrower is more specific
+		(self.0..=self.1)
+			.contains(port)
+			.then_some(u16::MAX as u32 - (self.1 - self.0) as u32)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_src_allow() {
+		let args = |allow: &str, deny: &str| AclArgs {
+			src_allow: allow.into(),
+			src_deny: deny.into(),
+			dst_allow: "".into(),
+			dst_deny: None,
+			dst_ports_allow: "".into(),
+			dst_ports_deny: "".into(),
+		};
+		let ip = |s: &str| IpAddr::from_str(s).unwrap();
+
+		let acl = args("", "").parse("").unwrap();
+		assert!(acl.check_src(ip("198.51.100.1")));
+
+		// others are denied
+		let acl = args("10.0.0.0/8", "").parse("").unwrap();
+		assert!(acl.check_src(ip("10.1.2.3")));
+		assert!(!acl.check_src(ip("198.51.100.1")));
+		assert!(!acl.check_src(ip("2001:db8::1")));
+
+		// denied within it still
+		let acl = args("10.0.0.0/8", "10.1.0.0/16").parse("").unwrap();
+		assert!(acl.check_src(ip("10.2.0.1")));
+		assert!(!acl.check_src(ip("10.1.2.3")));
+		assert!(!acl.check_src(ip("198.51.100.1")));
+
+		let acl = args("all", "").parse("").unwrap();
+		assert!(acl.check_src(ip("198.51.100.1")));
+	}
+
+	#[test]
+	fn test_acl() {
+		let acl = AclArgs {
+			src_allow: "192.0.2.0/24".into(),
+			src_deny: "all, 192.0.2.128/25".into(),
+			dst_allow: "10.1.2.3".into(),
+			dst_deny: None,
+			dst_ports_allow: "443".into(),
+			dst_ports_deny: "0-1023, 22".into(),
+		}
+		.parse("private")
+		.unwrap();
+
+		let ip = |s: &str| IpAddr::from_str(s).unwrap();
+		let sa = |s: &str| SocketAddr::new(ip(s), 443);
+
+		assert!(acl.check_src(ip("192.0.2.1")));
+		assert!(!acl.check_src(ip("192.0.2.129")));
+		assert!(!acl.check_src(ip("198.51.100.1")));
+		assert!(acl.check_src(ip("::ffff:192.0.2.1")));
+
+		assert!(acl.check_dst(sa("1.1.1.1")));
+		assert!(acl.check_dst(sa("10.1.2.3")));
+		assert!(!acl.check_dst(sa("10.1.2.4")));
+		assert!(!acl.check_dst(sa("::1")));
+		assert!(!acl.check_dst(sa("::ffff:127.0.0.1")));
+		assert!(acl.check_dst(sa("2001:db8::1")));
+
+		assert!(!acl.check_port(22));
+		assert!(!acl.check_port(80));
+		assert!(acl.check_port(443));
+		assert!(acl.check_port(8080));
+
+		assert!(Cidr::from_str("10.0.0.0/33").is_err());
+		assert!(Ports::from_str("2-1").is_err());
+	}
+}
@@ -0,0 +1,243 @@
+// binding of upstream sockets, to an address, to an interface (SO_BINDTODEVICE)
+// and/or with a fwmark (SO_MARK) for policy routing
+// interface and fwmark are linux only
+
+use std::{
+	fmt::Display,
+	future::Future,
+	io,
+	net::{IpAddr, SocketAddr},
+	pin::Pin,
+	str::FromStr,
+	sync::Arc,
+	time::Duration,
+};
+
+use log::*;
+
+use hickory_resolver::net::runtime::{
+	RuntimeProvider, TokioHandle, TokioRuntimeProvider, TokioTime, iocompat::AsyncIoTokioAsStd,
+};
+use tokio::{
+	net::{TcpSocket, TcpStream, UdpSocket},
+	time::timeout,
+};
+
+// same as hickory's default
+const DNS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default)]
+pub struct Bind {
+	pub addr: Option<IpAddr>,
+	pub iface: Option<String>,
+	pub mark: Option<u32>,
+}
+
+impl Display for Bind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mut sep = "";
+		if let Some(a) = self.addr {
+			write!(f, "{a}")?;
+			sep = " ";
+		}
+		if let Some(i) = &self.iface {
+			write!(f, "{sep}dev {i}")?;
+			sep = " ";
+		}
+		if let Some(m) = self.mark {
+			write!(f, "{sep}mark 0x{m:x}")?;
+		}
+		Ok(())
+	}
+}
+
+impl Bind {
+	// creates a socket suitable to connect to peer
+	pub fn tcp_socket(&self, peer: SocketAddr) -> Option<TcpSocket> {
+		let s = match peer {
+			SocketAddr::V4(_) => TcpSocket::new_v4(),
+			SocketAddr::V6(_) => TcpSocket::new_v6(),
+		}
+		.inspect_err(|e| error!("failed to create socket: {e}"))
+		.ok()?;
+		self.set_opts(&s)
+			.inspect_err(|e| error!("failed to bind to {self}: {e}"))
+			.ok()?;
+		if let Some(addr) = self.addr {
+			// reuse addr?
+			s.bind(SocketAddr::new(addr, 0))
+				.inspect_err(|e| error!("failed to bind to {addr}: {e}"))
+				.ok()?;
+		}
+		Some(s)
+	}
+
+	// a socket suitable to send to peers of the same family
+	pub fn udp_socket(&self, peer: SocketAddr) -> Option<UdpSocket> {
+		let local = match self.addr {
+			Some(a) if a.is_ipv4() == peer.is_ipv4() => SocketAddr::new(a, 0),
+			_ if peer.is_ipv4() => SocketAddr::from(([0u8; 4], 0)),
+			_ => SocketAddr::from(([0u16; 8], 0)),
+		};
+		let s = socket2::Socket::new(
+			socket2::Domain::for_address(local),
+			socket2::Type::DGRAM,
+			Some(socket2::Protocol::UDP),
+		)
+		.inspect_err(|e| error!("failed to create socket: {e}"))
+		.ok()?;
+		self.set_opts(&s)
+			.inspect_err(|e| error!("failed to bind to {self}: {e}"))
+			.ok()?;
+		s.set_nonblocking(true)
+			.and_then(|_| s.bind(&local.into()))
+			.inspect_err(|e| error!("failed to bind to {local}: {e}"))
+			.ok()?;
+		UdpSocket::from_std(s.into())
+			.inspect_err(|e| error!("failed to create socket: {e}"))
+			.ok()
+	}
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	fn set_opts<T: std::os::fd::AsFd>(&self, s: &T) -> io::Result<()> {
+		let s = socket2::SockRef::from(s);
+		if let Some(iface) = &self.iface {
+			s.bind_device(Some(iface.as_bytes()))?;
+		}
+		if let Some(mark) = self.mark {
+			s.set_mark(mark)?;
+		}
+		Ok(())
+	}
+
+	// parse_bind refuses iface and mark on other platforms
+	#[cfg(not(any(target_os = "linux", target_os = "android")))]
+	fn set_opts<T>(&self, _: &T) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// double Option, see parse_dns_conf
+// bind can be an address or an interface name
+// to do: test the address is actually bindable to fail early
+pub fn parse_bind(bind: &str, mark: u32) -> Option<Option<Bind>> {
+	if bind.is_empty() && mark == 0 {
+		return Some(None);
+	}
+	let mut b = Bind::default();
+	if let Ok(a) = IpAddr::from_str(bind) {
+		b.addr = Some(a);
+	} else if !bind.is_empty() {
+		// IFNAMSIZ includes the trailing NUL
+		if bind.len() >= 16 || bind.contains(['/', ' ', ':']) {
+			error!("invalid bind address or interface: {bind}");
+			return None;
+		}
+		b.iface = Some(bind.to_string());
+	}
+	if mark != 0 {
+		b.mark = Some(mark);
+	}
+	if cfg!(not(any(target_os = "linux", target_os = "android")))
+		&& (b.iface.is_some() || b.mark.is_some())
+	{
+		error!("binding to interface or fwmark is only supported on linux");
+		return None;
+	}
+	info!("bind upstream connections to {b}");
+	Some(Some(b))
+}
+
+// hickory runtime with our sockets, so dns queries go out the same way
+#[derive(Clone, Default)]
+pub struct Runtime {
+	inner: TokioRuntimeProvider,
+	bind: Option<Arc<Bind>>,
+}
+
+impl Runtime {
+	pub fn new(bind: Option<&Bind>) -> Self {
+		Self {
+			inner: TokioRuntimeProvider::default(),
+			bind: bind.map(|b| Arc::new(b.clone())),
+		}
+	}
+}
+
+impl RuntimeProvider for Runtime {
+	type Handle = TokioHandle;
+	type Timer = TokioTime;
+	type Udp = UdpSocket;
+	type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+	fn create_handle(&self) -> Self::Handle {
+		self.inner.create_handle()
+	}
+
+	fn connect_tcp(
+		&self,
+		server_addr: SocketAddr,
+		bind_addr: Option<SocketAddr>,
+		wait_for: Option<Duration>,
+	) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
+		let Some(bind) = self.bind.clone() else {
+			return self.inner.connect_tcp(server_addr, bind_addr, wait_for);
+		};
+		Box::pin(async move {
+			let s = match server_addr {
+				SocketAddr::V4(_) => TcpSocket::new_v4(),
+				SocketAddr::V6(_) => TcpSocket::new_v6(),
+			}?;
+			bind.set_opts(&s)?;
+			match (bind_addr, bind.addr) {
+				(Some(a), _) => s.bind(a)?,
+				(None, Some(a)) if a.is_ipv4() == server_addr.is_ipv4() => {
+					s.bind(SocketAddr::new(a, 0))?
+				}
+				_ => {}
+			}
+			s.set_nodelay(true)?;
+			match timeout(
+				wait_for.unwrap_or(DNS_CONNECT_TIMEOUT),
+				s.connect(server_addr),
+			)
+			.await
+			{
+				Ok(s) => Ok(AsyncIoTokioAsStd(s?)),
+				Err(_) => Err(io::Error::new(
+					io::ErrorKind::TimedOut,
+					"TCP connect timed out",
+				)),
+			}
+		})
+	}
+
+	fn bind_udp(
+		&self,
+		local_addr: SocketAddr,
+		server_addr: SocketAddr,
+	) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
+		let Some(bind) = self.bind.clone() else {
+			return self.inner.bind_udp(local_addr, server_addr);
+		};
+		Box::pin(async move {
+			let local_addr = match bind.addr {
+				Some(a)
+					if local_addr.ip().is_unspecified() && a.is_ipv4() == local_addr.is_ipv4() =>
+				{
+					SocketAddr::new(a, local_addr.port())
+				}
+				_ => local_addr,
+			};
+			let s = socket2::Socket::new(
+				socket2::Domain::for_address(local_addr),
+				socket2::Type::DGRAM,
+				Some(socket2::Protocol::UDP),
+			)?;
+			bind.set_opts(&s)?;
+			s.set_nonblocking(true)?;
+			s.bind(&local_addr.into())?;
+			UdpSocket::from_std(s.into())
+		})
+	}
+}
@@ -4,12 +4,14 @@ pub const SOCKS5_RSV: u8 = 0;
 pub const SOCKS5_NO_AUTH_REQUIRED: u8 = 0;
 
 pub const SOCKS5_CMD_CONNECT: u8 = 1;
+pub const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 3;
 
 pub const SOCKS5_ATYP_V4: u8 = 1;
 pub const SOCKS5_ATYP_DOMAINNAME: u8 = 3;
 pub const SOCKS5_ATYP_V6: u8 = 4;
 
 pub const SOCKS5_REP_SUCCEED: u8 = 0;
+pub const SOCKS5_REP_GENERAL_FAILURE: u8 = 1;
 pub const SOCKS5_REP_CMD_NOT_SUPPORTED: u8 = 7;
 
 use log::*;
@@ -1,15 +1,27 @@
 // https://datatracker.ietf.org/doc/html/rfc1928
 
+mod acl;
 mod addr;
+mod bind;
 mod client;
 mod common;
+mod limit;
+mod relay;
 mod server;
+mod stats;
+mod udp;
 mod upstream;
 
-pub use addr::{Dst, Addr};
+pub use acl::{Acl, AclArgs};
+pub use addr::{Dst, Addr, read_dst, write_dst};
+pub use bind::{Bind, parse_bind};
 pub use client::client_handshake;
-pub use server::server_handshake;
-pub use upstream::{Resolver, listen, connect, parse_bind, parse_dns_conf};
+pub use limit::{Limiter, Limits, Permit, accept};
+pub use relay::{Timeouts, Tracked, copy_bidirectional, relay, timeout};
+pub use server::{Cmd, server_handshake, server_handshake_udp, udp_reply, udp_reply_failure};
+pub use stats::{Metered, Scope, Session, Stats, serve_stats};
+pub use udp::{read_udp_header, write_udp_header};
+pub use upstream::{Resolver, bind_udp, connect, listen, lookup, parse_dns_conf, resolve};
 
 
 #[cfg(test)]
@@ -0,0 +1,198 @@
+// accepting connections with limits:
+//	max concurrent connections in total, stops accepting when reached
+//	max concurrent connections per client ip, refuses when reached
+//	new connections per second per client ip, token bucket, refuses when exhausted
+
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	net::{IpAddr, SocketAddr},
+	rc::Rc,
+	time::Duration,
+};
+
+use log::*;
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::Notify,
+	time::{Instant, sleep},
+};
+
+// when to prune idle clients from the table
+const PRUNE_THRESHOLD: usize = 0x1000;
+
+const BACKOFF_MIN: Duration = Duration::from_millis(10);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(clap::Args, Clone, Copy)]
+pub struct Limits {
+	/// max concurrent connections, 0 means unlimited
+	#[arg(long, env, default_value_t = 0)]
+	pub max_conns: usize,
+
+	/// max concurrent connections per client ip, 0 means unlimited
+	#[arg(long, env, default_value_t = 0)]
+	pub max_conns_per_ip: usize,
+
+	/// max new connections per second per client ip, 0 means unlimited
+	#[arg(long, env, default_value_t = 0)]
+	pub rate_per_ip: u32,
+}
+
+pub struct Limiter {
+	limits: Limits,
+	state: RefCell<State>,
+	released: Notify,
+}
+
+#[derive(Default)]
+struct State {
+	total: usize,
+	clients: HashMap<IpAddr, Client>,
+}
+
+struct Client {
+	conns: usize,
+	tokens: f32,
+	last: Instant,
+}
+
+// holds a slot until dropped
+pub struct Permit {
+	limiter: Rc<Limiter>,
+	ip: IpAddr,
+}
+
+impl Limiter {
+	pub fn new(limits: Limits) -> Rc<Self> {
+		Rc::new(Self {
+			limits,
+			state: RefCell::default(),
+			released: Notify::new(),
+		})
+	}
+
+	fn full(&self) -> bool {
+		self.limits.max_conns != 0 && self.state.borrow().total >= self.limits.max_conns
+	}
+
+	pub fn admit(self: &Rc<Self>, ip: IpAddr) -> Option<Permit> {
+		if self.full() {
+			debug!("{ip} refused, too many connections in total");
+			return None;
+		}
+		let mut state = self.state.borrow_mut();
+		let state = &mut *state;
+		let now = Instant::now();
+		let rate = self.limits.rate_per_ip as f32;
+		if state.clients.len() >= PRUNE_THRESHOLD {
+			state
+				.clients
+				.retain(|_, c| c.conns > 0 || c.refill(rate, now) < rate);
+		}
+		let c = state.clients.entry(ip).or_insert(Client {
+			conns: 0,
+			tokens: rate,
+			last: now,
+		});
+		if self.limits.max_conns_per_ip != 0 && c.conns >= self.limits.max_conns_per_ip {
+			debug!("{ip} refused, {} concurrent connections", c.conns);
+			return None;
+		}
+		if self.limits.rate_per_ip != 0 {
+			if c.refill(rate, now) < 1.0 {
+				debug!("{ip} refused, rate limited");
+				return None;
+			}
+			c.tokens -= 1.0;
+		}
+		c.conns += 1;
+		state.total += 1;
+		Some(Permit {
+			limiter: self.clone(),
+			ip,
+		})
+	}
+}
+
+impl Client {
+	fn refill(&mut self, rate: f32, now: Instant) -> f32 {
+		self.tokens = (self.tokens + (now - self.last).as_secs_f32() * rate).min(rate);
+		self.last = now;
+		self.tokens
+	}
+}
+
+impl Drop for Permit {
+	fn drop(&mut self) {
+		let mut state = self.limiter.state.borrow_mut();
+		state.total -= 1;
+		if let Some(c) = state.clients.get_mut(&self.ip) {
+			c.conns -= 1;
+			// nothing to remember without rate limit
+			if c.conns == 0 && self.limiter.limits.rate_per_ip == 0 {
+				state.clients.remove(&self.ip);
+			}
+		}
+		self.limiter.released.notify_one();
+	}
+}
+
+// accepts the next admitted connection
+// errors like EMFILE are logged and retried with backoff, instead of giving up
+pub async fn accept(l: &TcpListener, limiter: &Rc<Limiter>) -> (TcpStream, SocketAddr, Permit) {
+	let mut backoff = BACKOFF_MIN;
+	loop {
+		while limiter.full() {
+			limiter.released.notified().await;
+		}
+		match l.accept().await {
+			Ok((s, addr)) => {
+				backoff = BACKOFF_MIN;
+				if let Some(p) = limiter.admit(addr.ip()) {
+					return (s, addr, p);
+				}
+				// dropped
+			}
+			Err(e) => {
+				error!("error accepting: {e}, retry in {}ms", backoff.as_millis());
+				sleep(backoff).await;
+				backoff = (backoff * 2).min(BACKOFF_MAX);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test(start_paused = true)]
+	async fn test_limiter() {
+		let l = Limiter::new(Limits {
+			max_conns: 3,
+			max_conns_per_ip: 2,
+			rate_per_ip: 2,
+		});
+		let a: IpAddr = "192.0.2.1".parse().unwrap();
+		let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+		let p0 = l.admit(a).unwrap();
+		let p1 = l.admit(a).unwrap();
+		// per ip
+		assert!(l.admit(a).is_none());
+		drop(p0);
+		// rate
+		assert!(l.admit(a).is_none());
+		tokio::time::advance(Duration::from_millis(500)).await;
+		let _p2 = l.admit(a).unwrap();
+
+		let _p3 = l.admit(b).unwrap();
+		// total
+		assert!(l.full());
+		assert!(l.admit(b).is_none());
+		drop(p1);
+		assert!(!l.full());
+		assert!(l.admit(b).is_some());
+	}
+}
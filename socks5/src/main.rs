@@ -1,10 +1,14 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{net::SocketAddr, rc::Rc};
 
 use clap::Parser;
 use log::*;
-use tokio::{io::copy_bidirectional, net::TcpStream};
+use tokio::net::TcpStream;
 
-use socks5::{Resolver, connect, listen, parse_bind, parse_dns_conf, server_handshake};
+use socks5::{
+	Acl, AclArgs, Bind, Limiter, Limits, Metered, Resolver, Scope, Session, Stats, Timeouts,
+	accept, connect, listen, parse_bind, parse_dns_conf, relay, serve_stats, server_handshake,
+	timeout,
+};
 
 #[derive(Parser)]
 #[command(version = env!("REV"))]
@@ -15,9 +19,26 @@ struct Args {
 	#[clap(short, long, env, default_value = "")]
 	pub dns: String,
 
-	/// bind address for upstream connections
+	/// bind address or interface for upstream connections
 	#[clap(short, long, env, default_value = "")]
 	pub bind: String,
+
+	/// fwmark for upstream connections, 0 means none
+	#[clap(long, env, default_value_t = 0)]
+	pub fwmark: u32,
+
+	#[command(flatten)]
+	pub timeouts: Timeouts,
+
+	#[command(flatten)]
+	pub limits: Limits,
+
+	#[command(flatten)]
+	pub acl: AclArgs,
+
+	/// listen address of the stats endpoint, empty means disabled
+	#[clap(long, env, default_value = "")]
+	pub stats: String,
 }
 
 #[cfg(debug_assertions)]
@@ -31,41 +52,87 @@ async fn main() {
 		.init();
 
 	let args = Args::parse();
-	run_local(serv(&args.listen, &args.bind, &args.dns)).await;
+	run_local(serv(&args)).await;
 }
 
-async fn serv(l_addr: &str, bind: &str, dns: &str) -> Option<()> {
-	let bind: Option<IpAddr> = parse_bind(bind)?;
+async fn serv(args: &Args) -> Option<()> {
+	let bind = parse_bind(&args.bind, args.fwmark)?;
 
-	let dns = parse_dns_conf(dns)?;
+	let dns = parse_dns_conf(&args.dns, bind.as_ref())?;
 
-	let l = listen(l_addr).await?;
+	// intended for LAN usage, so private destinations are allowed by default
+	let acl = Rc::new(args.acl.parse("")?);
+
+	let stats = Stats::new("socks5");
+	if !args.stats.is_empty() {
+		tokio::task::spawn_local(serve_stats(args.stats.clone(), stats.clone()));
+	}
+
+	let l = listen(&args.listen).await?;
+	let limiter = Limiter::new(args.limits);
 
 	loop {
+		let bind = bind.clone();
 		let dns = dns.clone();
-		let (c, addr) = l.accept().await.unwrap();
-		tokio::task::spawn_local(handle(c, addr, bind, dns));
+		let (c, addr, permit) = accept(&l, &limiter).await;
+		if !acl.check_src(addr.ip()) {
+			info!("connection from {addr} denied");
+			continue;
+		}
+		let mut sess = stats.session();
+		sess.with(Scope::Client, addr.ip());
+		let acl = acl.clone();
+		let timeouts = args.timeouts;
+		tokio::task::spawn_local(async move {
+			let _permit = permit;
+			handle(c, addr, sess, bind, dns, acl, timeouts).await
+		});
 	}
 }
 
-async fn handle(mut c: TcpStream, addr: SocketAddr, bind: Option<IpAddr>, dns: Option<Resolver>) {
+async fn handle(
+	mut c: TcpStream,
+	addr: SocketAddr,
+	mut sess: Session,
+	bind: Option<Bind>,
+	dns: Option<Resolver>,
+	acl: Rc<Acl>,
+	timeouts: Timeouts,
+) {
 	let _ = c.set_nodelay(true);
-	let Some(dst) = server_handshake(&mut c).await else {
+	let Some(dst) = timeout("handshake", timeouts.handshake(), server_handshake(&mut c)).await
+	else {
 		error!("server handshake on connection from {addr} failed");
+		sess.fail();
 		return;
 	};
 	info!("new connection {} -> {}", addr, &dst);
-	let Some(mut u) = connect(bind, dns, &dst).await else {
+	sess.with(Scope::Destination, &dst.addr);
+	let Some(u) = timeout(
+		format_args!("connecting to {dst}"),
+		timeouts.connect(),
+		connect(bind.as_ref(), dns, &acl, &dst),
+	)
+	.await
+	else {
+		sess.fail();
 		return;
 	};
 	let _ = u.set_nodelay(true);
-	match copy_bidirectional(&mut c, &mut u).await {
-		Ok((u, d)) => {
+	match relay(Metered::new(c, &sess), u, timeouts.idle(), async |c, u| {
+		tokio::io::copy_bidirectional(c, u).await
+	})
+	.await
+	{
+		Some(Ok((u, d))) => {
 			info!("{addr} -> {dst} {u}/{d} bytes u/d");
 		}
-		Err(e) => {
+		Some(Err(e)) => {
 			error!("{addr} -> {dst} pipe error: {e}");
 		}
+		None => {
+			info!("{addr} -> {dst} idle timeout");
+		}
 	}
 }
 
@@ -0,0 +1,201 @@
+// relaying between two streams, with handshake/connect/idle timeouts
+// shared by socks5, mint and tater
+
+use std::{
+	cell::Cell,
+	fmt::Display,
+	io,
+	pin::Pin,
+	rc::Rc,
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use log::*;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+	select,
+	time::{Instant, sleep_until},
+};
+
+#[derive(clap::Args, Clone, Copy)]
+pub struct Timeouts {
+	/// handshake timeout in seconds, 0 means none
+	#[arg(long, env, default_value_t = 10)]
+	pub handshake_timeout: u64,
+
+	/// connect timeout in seconds, 0 means none
+	#[arg(long, env, default_value_t = 10)]
+	pub connect_timeout: u64,
+
+	/// idle timeout in seconds, 0 means none
+	#[arg(long, env, default_value_t = 600)]
+	pub idle_timeout: u64,
+}
+
+impl Timeouts {
+	pub fn handshake(&self) -> Option<Duration> {
+		secs(self.handshake_timeout)
+	}
+
+	pub fn connect(&self) -> Option<Duration> {
+		secs(self.connect_timeout)
+	}
+
+	pub fn idle(&self) -> Option<Duration> {
+		secs(self.idle_timeout)
+	}
+}
+
+fn secs(s: u64) -> Option<Duration> {
+	if s == 0 {
+		None
+	} else {
+		Some(Duration::from_secs(s))
+	}
+}
+
+// for the Option returning futures we use everywhere
+pub async fn timeout<T>(
+	what: impl Display,
+	t: Option<Duration>,
+	f: impl Future<Output = Option<T>>,
+) -> Option<T> {
+	let Some(t) = t else {
+		return f.await;
+	};
+	tokio::time::timeout(t, f)
+		.await
+		.inspect_err(|_| debug!("{what} timed out"))
+		.ok()?
+}
+
+// runs f, which should relay between a and b,
+// f is dropped if there's no traffic on either side for idle,
+// both sides are shut down afterwards regardless,
+// returns None if it was timed out
+pub async fn relay<A, B, R>(
+	a: A,
+	b: B,
+	idle: Option<Duration>,
+	f: impl AsyncFnOnce(&mut Tracked<A>, &mut Tracked<B>) -> R,
+) -> Option<R>
+where
+	A: AsyncRead + AsyncWrite + Unpin,
+	B: AsyncRead + AsyncWrite + Unpin,
+{
+	let last = Rc::new(Cell::new(Instant::now()));
+	let mut a = Tracked::new(a, last.clone());
+	let mut b = Tracked::new(b, last.clone());
+
+	let r = match idle {
+		None => Some(f(&mut a, &mut b).await),
+		Some(idle) => select! {
+			r = f(&mut a, &mut b) => Some(r),
+			_ = watchdog(&last, idle) => {
+				debug!("idle for {}s, closing", idle.as_secs());
+				None
+			}
+		},
+	};
+
+	// errors are expected if they're already shut down
+	let _ = tokio::join!(a.inner.shutdown(), b.inner.shutdown());
+	r
+}
+
+pub async fn copy_bidirectional<A, B>(a: A, b: B, idle: Option<Duration>) -> Option<(u64, u64)>
+where
+	A: AsyncRead + AsyncWrite + Unpin,
+	B: AsyncRead + AsyncWrite + Unpin,
+{
+	relay(a, b, idle, async |a, b| {
+		tokio::io::copy_bidirectional(a, b)
+			.await
+			.inspect_err(|e| debug!("error copying: {e}"))
+			.ok()
+	})
+	.await?
+}
+
+async fn watchdog(last: &Cell<Instant>, idle: Duration) {
+	loop {
+		let deadline = last.get() + idle;
+		if Instant::now() >= deadline {
+			return;
+		}
+		sleep_until(deadline).await;
+	}
+}
+
+// records the time of last activity
+pub struct Tracked<T> {
+	inner: T,
+	last: Rc<Cell<Instant>>,
+}
+
+impl<T> Tracked<T> {
+	fn new(inner: T, last: Rc<Cell<Instant>>) -> Self {
+		Self { inner, last }
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let before = buf.filled().len();
+		let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+		if buf.filled().len() > before {
+			self.last.set(Instant::now());
+		}
+		r
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = r
+			&& n > 0
+		{
+			self.last.set(Instant::now());
+		}
+		r
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{AsyncReadExt, duplex};
+
+	use super::*;
+
+	#[tokio::test(start_paused = true)]
+	async fn test_idle() {
+		let (a, mut a_peer) = duplex(0x100);
+		let (b, _b_peer) = duplex(0x100);
+
+		a_peer.write_all(b"hello").await.unwrap();
+		let r = copy_bidirectional(a, b, Some(Duration::from_secs(3))).await;
+		assert_eq!(r, None);
+
+		// both sides should be shut down
+		let mut buf = [0u8; 8];
+		assert_eq!(a_peer.read(&mut buf).await.unwrap(), 0);
+	}
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use bytes::BytesMut;
 use log::*;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -7,9 +9,50 @@ use crate::{addr::*, common::*};
 // VER, REP, RSV, ATYP, BND.ADDR, BND.PORT
 const REP_LEN: usize = 4 + 4 + 2;
 
+pub enum Cmd<'a> {
+	Connect(Dst<'a>),
+	// where the client would send datagrams from, usually all zeros
+	UdpAssociate(Dst<'a>),
+}
+
 pub async fn server_handshake<'a, T: AsyncRead + AsyncWrite + Unpin>(
 	io: &mut T,
 ) -> Option<Dst<'a>> {
+	match handshake(io, false).await? {
+		Cmd::Connect(dst) => Some(dst),
+		Cmd::UdpAssociate(_) => unreachable!(),
+	}
+}
+
+// UDP ASSOCIATE is taken as well, to be replied with udp_reply()
+pub async fn server_handshake_udp<'a, T: AsyncRead + AsyncWrite + Unpin>(
+	io: &mut T,
+) -> Option<Cmd<'a>> {
+	handshake(io, true).await
+}
+
+// with the address datagrams should be sent to
+pub async fn udp_reply<T: AsyncWrite + Unpin>(io: &mut T, bound: SocketAddr) -> Option<()> {
+	// in one write, some clients read it in one go
+	let mut buf = vec![SOCKS5_VER, SOCKS5_REP_SUCCEED, SOCKS5_RSV];
+	write_dst(&mut buf, &Dst::from((bound.ip(), bound.port()))).await?;
+	io.write_all(&buf)
+		.await
+		.inspect_err(|e| error!("error writting REP: {e}"))
+		.ok()
+}
+
+// when the association can't be made
+pub async fn udp_reply_failure<T: AsyncWrite + Unpin>(io: &mut T) -> Option<()> {
+	reply(&mut [0u8; REP_LEN], io, SOCKS5_REP_GENERAL_FAILURE)
+		.await
+		.ok()
+}
+
+async fn handshake<'a, T: AsyncRead + AsyncWrite + Unpin>(
+	io: &mut T,
+	udp: bool,
+) -> Option<Cmd<'a>> {
 	let mut buf = [0u8; REP_LEN];
 
 	// VER NMETHODS
@@ -20,7 +63,7 @@ pub async fn server_handshake<'a, T: AsyncRead + AsyncWrite + Unpin>(
 
 	// HTTP CONNECT support
 	if eq_ignore_ascii_case(&buf[0..2], "CO") {
-		return connect_handshake(io).await;
+		return connect_handshake(io).await.map(Cmd::Connect);
 	}
 	expect("VER", buf[0], SOCKS5_VER)?;
 	// ignored
@@ -46,6 +89,11 @@ pub async fn server_handshake<'a, T: AsyncRead + AsyncWrite + Unpin>(
 		.ok()?;
 	expect("VER", buf[0], SOCKS5_VER)?;
 	expect("RSV", buf[2], SOCKS5_RSV)?;
+	if udp && buf[1] == SOCKS5_CMD_UDP_ASSOCIATE {
+		let dst = read_dst(io).await?;
+		debug!("SOCKS5 UDP ASSOCIATE {}", &dst);
+		return Some(Cmd::UdpAssociate(dst));
+	}
 	if expect("CMD", buf[1], SOCKS5_CMD_CONNECT).is_none() {
 		reply(&mut buf, io, SOCKS5_REP_CMD_NOT_SUPPORTED)
 			.await
@@ -57,14 +105,10 @@ pub async fn server_handshake<'a, T: AsyncRead + AsyncWrite + Unpin>(
 
 	reply(&mut buf, io, SOCKS5_REP_SUCCEED).await.ok()?;
 
-	Some(dst)
+	Some(Cmd::Connect(dst))
 }
 
-async fn reply<T: AsyncRead + AsyncWrite + Unpin>(
-	buf: &mut [u8],
-	io: &mut T,
-	rep: u8,
-) -> io::Result<()> {
+async fn reply<T: AsyncWrite + Unpin>(buf: &mut [u8], io: &mut T, rep: u8) -> io::Result<()> {
 	buf[0] = SOCKS5_VER;
 	buf[1] = rep;
 	buf[2] = SOCKS5_RSV;
@@ -0,0 +1,314 @@
+// traffic accounting, per client, per destination and per upstream
+// exposed over HTTP in prometheus text format
+
+use std::{
+	cell::RefCell,
+	collections::BTreeMap,
+	fmt::Write as _,
+	io,
+	pin::Pin,
+	rc::Rc,
+	task::{Context, Poll},
+};
+
+use log::*;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+	net::TcpStream,
+	task,
+};
+
+use crate::{Limiter, Limits, accept, listen};
+
+// idle entries are dropped beyond this, so destinations can't grow it forever
+const MAX_KEYS: usize = 0x1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+	Client,
+	Destination,
+	Upstream,
+}
+
+impl Scope {
+	fn name(&self) -> &'static str {
+		match self {
+			Scope::Client => "client",
+			Scope::Destination => "destination",
+			Scope::Upstream => "upstream",
+		}
+	}
+}
+
+// name, type, value
+type Metric = (&'static str, &'static str, fn(&Counters) -> u64);
+
+const METRICS: &[Metric] = &[
+	("connections_active", "gauge", |c| c.active),
+	("connections_total", "counter", |c| c.total),
+	("failures_total", "counter", |c| c.failures),
+	("bytes_up_total", "counter", |c| c.up),
+	("bytes_down_total", "counter", |c| c.down),
+];
+
+#[derive(Default)]
+struct Counters {
+	up: u64,
+	down: u64,
+	active: u64,
+	total: u64,
+	failures: u64,
+}
+
+pub struct Stats {
+	name: &'static str,
+	counters: RefCell<BTreeMap<(Scope, String), Counters>>,
+}
+
+impl Stats {
+	// name is used as the prefix of metrics
+	pub fn new(name: &'static str) -> Rc<Self> {
+		Rc::new(Self {
+			name,
+			counters: RefCell::default(),
+		})
+	}
+
+	pub fn session(self: &Rc<Self>) -> Session {
+		Session {
+			stats: self.clone(),
+			keys: Vec::with_capacity(3),
+		}
+	}
+
+	fn update(&self, keys: &[(Scope, String)], f: impl Fn(&mut Counters)) {
+		let mut counters = self.counters.borrow_mut();
+		for k in keys {
+			if let Some(c) = counters.get_mut(k) {
+				f(c);
+			}
+		}
+	}
+
+	fn render(&self) -> String {
+		let counters = self.counters.borrow();
+		let mut r = String::with_capacity(0x100 + counters.len() * 0x100);
+		let n = self.name;
+		for (metric, t, f) in METRICS {
+			let _ = writeln!(r, "# TYPE {n}_{metric} {t}");
+			for ((scope, key), c) in counters.iter() {
+				let _ = writeln!(
+					r,
+					"{n}_{metric}{{scope=\"{}\",key=\"{}\"}} {}",
+					scope.name(),
+					escape(key),
+					f(c)
+				);
+			}
+		}
+		r
+	}
+}
+
+fn escape(s: &str) -> String {
+	s.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+// a connection, counted as active under each key until dropped
+pub struct Session {
+	stats: Rc<Stats>,
+	keys: Vec<(Scope, String)>,
+}
+
+impl Session {
+	pub fn with(&mut self, scope: Scope, key: impl ToString) {
+		let key = (scope, key.to_string());
+		let mut counters = self.stats.counters.borrow_mut();
+		if counters.len() >= MAX_KEYS && !counters.contains_key(&key) {
+			counters.retain(|_, c| c.active > 0);
+		}
+		let c = counters.entry(key.clone()).or_default();
+		c.active += 1;
+		c.total += 1;
+		drop(counters);
+		self.keys.push(key);
+	}
+
+	// counted against every key so far
+	pub fn fail(&self) {
+		self.stats.update(&self.keys, |c| c.failures += 1);
+	}
+
+	// for what's not through Metered, like datagrams
+	pub fn count(&self, up: u64, down: u64) {
+		self.stats.update(&self.keys, |c| {
+			c.up += up;
+			c.down += down;
+		});
+	}
+}
+
+impl Drop for Session {
+	fn drop(&mut self) {
+		self.stats.update(&self.keys, |c| c.active -= 1);
+	}
+}
+
+// wraps the client facing side of a connection
+// read from it counts as up, written to it counts as down
+pub struct Metered<'a, T> {
+	inner: T,
+	session: &'a Session,
+	// the other way around, for the destination facing side
+	reversed: bool,
+}
+
+impl<'a, T> Metered<'a, T> {
+	pub fn new(inner: T, session: &'a Session) -> Self {
+		Self {
+			inner,
+			session,
+			reversed: false,
+		}
+	}
+
+	// when the client facing side isn't the plain one, like in a tunnel
+	pub fn upstream(inner: T, session: &'a Session) -> Self {
+		Self {
+			inner,
+			session,
+			reversed: true,
+		}
+	}
+
+	fn count(&self, read: usize, written: usize) {
+		let (up, down) = if self.reversed {
+			(written, read)
+		} else {
+			(read, written)
+		};
+		self.session.count(up as u64, down as u64);
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Metered<'_, T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let before = buf.filled().len();
+		let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+		let n = buf.filled().len() - before;
+		if n > 0 {
+			self.count(n, 0);
+		}
+		r
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Metered<'_, T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = r
+			&& n > 0
+		{
+			self.count(0, n);
+		}
+		r
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+// the stats endpoint, any request gets the metrics
+// should be spawned in a local set
+pub async fn serve_stats(addr: String, stats: Rc<Stats>) -> Option<()> {
+	let l = listen(&addr).await?;
+	// it's for local scrapers, a handful is enough
+	let limiter = Limiter::new(Limits {
+		max_conns: 8,
+		max_conns_per_ip: 0,
+		rate_per_ip: 0,
+	});
+	loop {
+		let (s, _, permit) = accept(&l, &limiter).await;
+		let stats = stats.clone();
+		task::spawn_local(async move {
+			let _permit = permit;
+			let _ = respond(s, &stats).await;
+		});
+	}
+}
+
+async fn respond(mut s: TcpStream, stats: &Stats) -> io::Result<()> {
+	// request is not parsed, just wait for it
+	let mut buf = [0u8; 0x400];
+	let _ = s.read(&mut buf).await?;
+	let body = stats.render();
+	let head = format!(
+		"HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+		body.len()
+	);
+	s.write_all(head.as_bytes()).await?;
+	s.write_all(body.as_bytes()).await?;
+	s.shutdown()
+		.await
+		.inspect_err(|e| debug!("stats: error shutting down: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_stats() {
+		let stats = Stats::new("test");
+		let (a, mut b) = duplex(0x100);
+		{
+			let mut sess = stats.session();
+			sess.with(Scope::Client, "192.0.2.1");
+			sess.with(Scope::Destination, "example.com");
+			let mut m = Metered::new(a, &sess);
+			b.write_all(b"hello").await.unwrap();
+			let mut buf = [0u8; 5];
+			m.read_exact(&mut buf).await.unwrap();
+			m.write_all(b"hi").await.unwrap();
+			sess.fail();
+			let r = stats.render();
+			assert!(r.contains("test_connections_active{scope=\"client\",key=\"192.0.2.1\"} 1\n"));
+		}
+		let r = stats.render();
+		assert!(r.contains("test_connections_active{scope=\"client\",key=\"192.0.2.1\"} 0\n"));
+		assert!(r.contains("test_bytes_up_total{scope=\"destination\",key=\"example.com\"} 5\n"));
+		assert!(r.contains("test_bytes_down_total{scope=\"client\",key=\"192.0.2.1\"} 2\n"));
+		assert!(r.contains("test_failures_total{scope=\"destination\",key=\"example.com\"} 1\n"));
+
+		// reversed
+		let (a, mut b) = duplex(0x100);
+		let mut sess = stats.session();
+		sess.with(Scope::Destination, "example.org");
+		let mut m = Metered::upstream(a, &sess);
+		m.write_all(b"hello").await.unwrap();
+		b.write_all(b"hi").await.unwrap();
+		let mut buf = [0u8; 2];
+		m.read_exact(&mut buf).await.unwrap();
+		let r = stats.render();
+		assert!(r.contains("test_bytes_up_total{scope=\"destination\",key=\"example.org\"} 5\n"));
+		assert!(r.contains("test_bytes_down_total{scope=\"destination\",key=\"example.org\"} 2\n"));
+	}
+}
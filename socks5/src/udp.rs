@@ -0,0 +1,44 @@
+// the header of datagrams, RSV FRAG ATYP DST.ADDR DST.PORT
+
+use log::*;
+
+use crate::addr::*;
+
+// takes the header from the front of buf, what's left is the data
+// fragments are not supported, they're dropped
+pub async fn read_udp_header<'a>(buf: &mut &[u8]) -> Option<Dst<'a>> {
+	let [_, _, frag, ..] = **buf else {
+		debug!("datagram too short");
+		return None;
+	};
+	if frag != 0 {
+		debug!("fragmented datagram, dropped");
+		return None;
+	}
+	*buf = &buf[3..];
+	read_dst(buf).await
+}
+
+pub async fn write_udp_header(buf: &mut Vec<u8>, dst: &Dst<'_>) -> Option<()> {
+	buf.extend_from_slice(&[0, 0, 0]);
+	write_dst(buf, dst).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_udp_header() {
+		let dst: Dst = ("example.com", 53).into();
+		let mut buf = Vec::new();
+		write_udp_header(&mut buf, &dst).await.unwrap();
+		buf.extend_from_slice(b"hello");
+		let mut r = &buf[..];
+		assert_eq!(dst, read_udp_header(&mut r).await.unwrap());
+		assert_eq!(b"hello", r);
+
+		buf[2] = 1;
+		assert!(read_udp_header(&mut &buf[..]).await.is_none());
+	}
+}
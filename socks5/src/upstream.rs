@@ -11,26 +11,21 @@ use log::*;
 use hickory_resolver::{
 	self as resolver,
 	config::{ConnectionConfig, NameServerConfig, ResolveHosts, ResolverConfig},
-	net::runtime::TokioRuntimeProvider,
 	proto::rr::RData,
 };
-use tokio::net::{TcpListener, TcpSocket, TcpStream, lookup_host};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, lookup_host};
 
-use crate::{Addr, Dst};
+use crate::{Acl, Addr, Bind, Dst, bind::Runtime};
 
-pub type Resolver = resolver::Resolver<TokioRuntimeProvider>;
+pub type Resolver = resolver::Resolver<Runtime>;
 
 pub async fn connect(
-	bind: Option<IpAddr>,
+	bind: Option<&Bind>,
 	dns: Option<Resolver>,
+	acl: &Acl,
 	dst: &Dst<'_>,
 ) -> Option<TcpStream> {
-	let addrs = match &dst.addr {
-		Addr::Domain(host) => resolve(bind, dns, host, dst.port).await?,
-		Addr::DomainOwned(host) => resolve(bind, dns, host, dst.port).await?,
-		Addr::V4(a) => vec![SocketAddr::new(IpAddr::V4(*a), dst.port)],
-		Addr::V6(a) => vec![SocketAddr::new(IpAddr::V6(*a), dst.port)],
-	};
+	let addrs = lookup(bind, dns, acl, dst).await?;
 	match bind {
 		None => TcpStream::connect(addrs.as_slice())
 			.await
@@ -40,20 +35,7 @@ pub async fn connect(
 			.ok(),
 		Some(bind) => {
 			for a in addrs {
-				let s = match bind {
-					IpAddr::V4(_) => TcpSocket::new_v4(),
-					IpAddr::V6(_) => TcpSocket::new_v6(),
-				}
-				.inspect_err(|e| {
-					error!("failed to create socket: {e}");
-				})
-				.ok()?;
-				// reuse addr?
-				s.bind(SocketAddr::new(bind, 0))
-					.inspect_err(|e| {
-						error!("failed to bind to {bind}: {e}");
-					})
-					.ok()?;
+				let s = bind.tcp_socket(a)?;
 				if let Ok(s) = s.connect(a).await.inspect_err(|e| {
 					error!("failed to connect to \"{dst}\"({a}): {e}");
 				}) {
@@ -65,13 +47,49 @@ pub async fn connect(
 	}
 }
 
-async fn resolve(
-	bind: Option<IpAddr>,
+// addresses of dst allowed by the acl
+pub async fn lookup(
+	bind: Option<&Bind>,
+	dns: Option<Resolver>,
+	acl: &Acl,
+	dst: &Dst<'_>,
+) -> Option<Vec<SocketAddr>> {
+	if !acl.check_port(dst.port) {
+		info!("connection to \"{dst}\" denied by port");
+		return None;
+	}
+	let family = bind.and_then(|b| b.addr);
+	let mut addrs = match &dst.addr {
+		Addr::Domain(host) => resolve(family, dns, host, dst.port).await?,
+		Addr::DomainOwned(host) => resolve(family, dns, host, dst.port).await?,
+		Addr::V4(a) => vec![SocketAddr::new(IpAddr::V4(*a), dst.port)],
+		Addr::V6(a) => vec![SocketAddr::new(IpAddr::V6(*a), dst.port)],
+	};
+	// checked after resolution, so domains pointing to denied addresses are denied as well
+	addrs.retain(|a| acl.check_dst(*a));
+	if addrs.is_empty() {
+		info!("connection to \"{dst}\" denied by address");
+		return None;
+	}
+	Some(addrs)
+}
+
+// for datagrams to peers of the same family, bound like connections
+pub fn bind_udp(bind: Option<&Bind>, peer: SocketAddr) -> Option<UdpSocket> {
+	match bind {
+		Some(bind) => bind.udp_socket(peer),
+		None => Bind::default().udp_socket(peer),
+	}
+}
+
+// family: only addresses of the same family as the bind address are wanted
+pub async fn resolve(
+	family: Option<IpAddr>,
 	dns: Option<Resolver>,
 	host: &str,
 	port: u16,
 ) -> Option<Vec<SocketAddr>> {
-	let addrs: Vec<SocketAddr> = match (dns, bind) {
+	let addrs: Vec<SocketAddr> = match (dns, family) {
 		(None, None) => lookup_host(format!("{host}:{port}"))
 			.await
 			.inspect_err(|e| {
@@ -93,7 +111,7 @@ async fn resolve(
 				error!("failed to resolve \"{host}\": {e}");
 			})
 			.ok()?
-			.filter(SocketAddr::is_ipv4)
+			.filter(SocketAddr::is_ipv6)
 			.collect(),
 		(Some(dns), None) => dns
 			.lookup_ip(host)
@@ -149,7 +167,8 @@ async fn resolve(
 // None means there's error
 //		for example a typo
 //		in this case, if caller fallbacks, it could be unwanted dns leak
-pub fn parse_dns_conf(dns: &str) -> Option<Option<Resolver>> {
+// bind applies to the sockets of the resolver as well
+pub fn parse_dns_conf(dns: &str, bind: Option<&Bind>) -> Option<Option<Resolver>> {
 	if dns.is_empty() {
 		return Some(None);
 	}
@@ -157,8 +176,7 @@ pub fn parse_dns_conf(dns: &str) -> Option<Option<Resolver>> {
 	let nsc: Vec<_> = dns
 		.split(',')
 		.filter_map(|s| {
-			// ConnectionConfig does support bind address but not interface
-			// not useful
+			// binding is done by our Runtime, which supports interface as well
 			let mut cc = ConnectionConfig::udp();
 			let addr = if let Ok(a) = SocketAddr::from_str(s) {
 				info!("dns server: {a}");
@@ -179,7 +197,7 @@ pub fn parse_dns_conf(dns: &str) -> Option<Option<Resolver>> {
 		return None;
 	}
 	let rc = ResolverConfig::from_parts(None, vec![], nsc);
-	let mut b = Resolver::builder_with_config(rc, TokioRuntimeProvider::default());
+	let mut b = Resolver::builder_with_config(rc, Runtime::new(bind));
 	let ro = b.options_mut();
 	ro.use_hosts_file = ResolveHosts::Never;
 	ro.preserve_intermediates = false;
@@ -192,20 +210,6 @@ pub fn parse_dns_conf(dns: &str) -> Option<Option<Resolver>> {
 	))
 }
 
-// double Option for the same reason as above
-// to do: test the address is actually bindable to fail early
-// to do: support binding to interface
-pub fn parse_bind(bind: &str) -> Option<Option<IpAddr>> {
-	if bind.is_empty() {
-		return Some(None);
-	}
-	Some(Some(
-		IpAddr::from_str(bind)
-			.inspect_err(|e| error!("error parsing bind address: {e}"))
-			.ok()?,
-	))
-}
-
 pub async fn listen(addr: &str) -> Option<TcpListener> {
 	let l = TcpListener::bind(addr)
 		.await
@@ -2,7 +2,8 @@ use std::{cell::RefCell, rc::Rc, time::Duration};
 
 use clap::Parser;
 use log::*;
-use tokio::{signal::ctrl_c, sync::oneshot, task};
+use socks5::{Stats, Timeouts, serve_stats};
+use tokio::{select, signal::ctrl_c, sync::oneshot, task};
 
 use tater::{
 	fake_dns,
@@ -34,6 +35,13 @@ struct Args {
 
 	#[clap(short, long, env, default_value = "127.0.0.1:1080")]
 	pub socks5: String,
+
+	#[command(flatten)]
+	pub timeouts: Timeouts,
+
+	/// listen address of the stats endpoint, empty means disabled
+	#[clap(long, env, default_value = "")]
+	pub stats: String,
 }
 
 #[cfg(debug_assertions)]
@@ -53,11 +61,14 @@ async fn main() {
 		args.fake_pool_init_cap,
 	)));
 
+	let stats = Stats::new("tater");
+
 	let local = task::LocalSet::new();
 
 	let (abort_tx0, abort0) = oneshot::channel();
 	let (abort_tx1, abort1) = oneshot::channel();
 	let (abort_tx2, abort2) = oneshot::channel();
+	let (abort_tx3, abort3) = oneshot::channel();
 
 	local.spawn_local(async move {
 		ctrl_c().await.unwrap();
@@ -65,6 +76,8 @@ async fn main() {
 		abort_tx0.send(()).unwrap();
 		abort_tx1.send(()).unwrap();
 		abort_tx2.send(()).unwrap();
+		// not listening if stats is disabled
+		let _ = abort_tx3.send(());
 	});
 	local.spawn_local(fake_dns(
 		abort0,
@@ -76,6 +89,8 @@ async fn main() {
 		args.tproxy_listen.parse().unwrap(),
 		pool.clone(),
 		args.socks5.parse().unwrap(),
+		args.timeouts,
+		stats.clone(),
 	));
 	local.spawn_local(gc_task(
 		abort2,
@@ -84,5 +99,14 @@ async fn main() {
 		Duration::from_secs(args.fake_pool_gc_interval),
 	));
 
+	if !args.stats.is_empty() {
+		local.spawn_local(async move {
+			select! {
+				_ = serve_stats(args.stats, stats) => {}
+				_ = abort3 => {}
+			}
+		});
+	}
+
 	local.await;
 }
@@ -15,6 +15,7 @@ use std::{
 
 use log::*;
 use socket2::Socket;
+use socks5::{Metered, Scope, Session, Stats, Timeouts, relay, timeout};
 use tokio::{
 	io::{AsyncReadExt, AsyncWriteExt, Result, copy_bidirectional},
 	net::{TcpListener, TcpStream},
@@ -30,6 +31,8 @@ pub async fn tproxy(
 	bind_addr: SocketAddr,
 	pool: Rc<RefCell<FakePool>>,
 	socks_addr: SocketAddr,
+	timeouts: Timeouts,
+	stats: Rc<Stats>,
 ) -> Option<()> {
 	// note: I tried creating the socket using socket2, but it didn't work
 	//	accept() always returns os error 22
@@ -43,7 +46,7 @@ pub async fn tproxy(
 
 	loop {
 		select! {
-			r = s.accept() =>  handle_conn(r, &pool, socks_addr),
+			r = s.accept() =>  handle_conn(r, &pool, socks_addr, timeouts, &stats),
 			_ = &mut quit_signal => {
 				info!("exiting");
 				break;
@@ -58,6 +61,8 @@ fn handle_conn(
 	r: Result<(TcpStream, SocketAddr)>,
 	pool: &Rc<RefCell<FakePool>>,
 	socks_addr: SocketAddr,
+	timeouts: Timeouts,
+	stats: &Rc<Stats>,
 ) {
 	let Ok((stream, addr)) = r.inspect_err(|e| error!("tcp accept error: {e}")) else {
 		return;
@@ -73,7 +78,18 @@ fn handle_conn(
 		return;
 	};
 	info!("\t{}", &name);
-	task::spawn_local(proxy(stream, name, dst.port(), socks_addr));
+	let mut sess = stats.session();
+	sess.with(Scope::Client, addr.ip());
+	sess.with(Scope::Destination, &name);
+	sess.with(Scope::Upstream, socks_addr);
+	task::spawn_local(async move {
+		if proxy(stream, name, dst.port(), socks_addr, timeouts, &sess)
+			.await
+			.is_none()
+		{
+			sess.fail();
+		}
+	});
 }
 
 const SOCKS5_VERSION: u8 = 0x05;
@@ -93,16 +109,47 @@ const SOCKS5_CONNECT_HEAD: &[u8] = &[
 ];
 
 async fn proxy(
-	mut stream: TcpStream,
+	stream: TcpStream,
 	dest_name: String,
 	dest_port: u16,
 	socks_addr: SocketAddr,
+	timeouts: Timeouts,
+	sess: &Session,
 ) -> Option<()> {
 	let _ = stream.set_nodelay(true);
 
-	let mut socks = TcpStream::connect(socks_addr).await.unwrap();
+	let mut socks = timeout("connecting to socks5", timeouts.connect(), async {
+		TcpStream::connect(socks_addr)
+			.await
+			.inspect_err(|e| error!("failed to connect to socks5 {socks_addr}: {e}"))
+			.ok()
+	})
+	.await?;
 	let _ = socks.set_nodelay(true);
 
+	timeout(
+		"socks5 handshake",
+		timeouts.handshake(),
+		handshake(&mut socks, &dest_name, dest_port),
+	)
+	.await?;
+
+	if relay(
+		socks,
+		Metered::new(stream, sess),
+		timeouts.idle(),
+		async |socks, stream| copy_bidirectional(socks, stream).await,
+	)
+	.await
+	.is_none()
+	{
+		info!("idle timeout: {dest_name}:{dest_port}");
+	}
+
+	Some(())
+}
+
+async fn handshake(socks: &mut TcpStream, dest_name: &str, dest_port: u16) -> Option<()> {
 	let mut buf = vec![0u8; 0x110];
 
 	socks.write_all(SOCKS5_CLIENT_HELLO).await.ok()?;
@@ -162,9 +209,5 @@ async fn proxy(
 	};
 	socks.read_exact(&mut buf[..2]).await.ok()?;
 
-	drop(buf);
-
-	copy_bidirectional(&mut socks, &mut stream).await.ok()?;
-
 	Some(())
 }